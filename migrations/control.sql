@@ -0,0 +1,6 @@
+-- Runtime switches read without a restart: `paused` (true/false) and the
+-- one-shot `snooze` (skip, or minutes to shift the next run). Optional.
+CREATE TABLE IF NOT EXISTS control (
+    key text PRIMARY KEY,
+    value text NOT NULL
+);
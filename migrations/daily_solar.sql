@@ -0,0 +1,9 @@
+-- Each day's solar times, so a day's sunrise is fetched at most once.
+-- Optional: without this table every run asks the sunrise API.
+CREATE TABLE IF NOT EXISTS daily_solar (
+    day date PRIMARY KEY,
+    sunrise timestamptz NOT NULL,
+    sunset timestamptz,
+    civil_twilight_begin timestamptz,
+    solar_noon timestamptz
+);
@@ -0,0 +1,7 @@
+-- The address each light with a mac was last discovered at, to warn when
+-- it moves. Optional.
+CREATE TABLE IF NOT EXISTS light_address (
+    name text PRIMARY KEY,
+    addr text NOT NULL,
+    seen_at timestamptz NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Tags each log row with the deployment that wrote it (INSTANCE_NAME or
+-- the hostname). Until this runs, rows are written without it.
+ALTER TABLE log ADD COLUMN IF NOT EXISTS instance text;
@@ -0,0 +1,10 @@
+-- Each job's solar event and target per day, recorded by OBSERVE_ONLY.
+CREATE TABLE IF NOT EXISTS observation (
+    day date NOT NULL,
+    job text NOT NULL,
+    event text NOT NULL,
+    event_time timestamptz NOT NULL,
+    target timestamptz NOT NULL,
+    observed_at timestamptz NOT NULL,
+    PRIMARY KEY (day, job)
+);
@@ -0,0 +1,12 @@
+-- Each light's outcome per run, read by --replay-failures. Optional.
+CREATE TABLE IF NOT EXISTS run_result (
+    run_at timestamptz NOT NULL,
+    job text NOT NULL,
+    name text NOT NULL,
+    host_id text NOT NULL,
+    command text NOT NULL,
+    status text NOT NULL,
+    error text,
+    latency_ms double precision NOT NULL,
+    PRIMARY KEY (run_at, name)
+);
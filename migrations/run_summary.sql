@@ -0,0 +1,11 @@
+-- One row per finished run with light counts and timings. Optional.
+CREATE TABLE IF NOT EXISTS run_summary (
+    run_at timestamptz PRIMARY KEY,
+    job text NOT NULL,
+    total integer NOT NULL,
+    succeeded integer NOT NULL,
+    failed integer NOT NULL,
+    event_time timestamptz NOT NULL,
+    target timestamptz NOT NULL,
+    duration_ms double precision NOT NULL
+);
@@ -0,0 +1,46 @@
+use chrono::{DateTime, Local};
+use std::sync::Mutex;
+
+/// Source of the current time, so scheduling can be driven deterministically.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// The host's wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock that only moves when told to: stopped for the `--now` testing
+/// flag, set or advanced by scheduling tests.
+#[derive(Debug)]
+pub struct FakeClock {
+    now: Mutex<DateTime<Local>>,
+}
+
+impl FakeClock {
+    pub fn new(now: DateTime<Local>) -> Self {
+        FakeClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Local>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,805 @@
+use crate::console::Console;
+use crate::db::EventLog;
+use crate::error::AppError;
+use crate::events::EventBus;
+use crate::geocode::geocode_city;
+use crate::light::{LightCommand, LightOrder, LightsCache};
+use crate::notify::{notifier_from_env, Notifier};
+use crate::plan::FailThreshold;
+use crate::secret::decrypt_password;
+use crate::sunrise::{CircuitBreaker, SolarCache};
+use crate::transport::{SocketBuffers, UdpBind};
+use crate::weather::WeatherCache;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use serde_json::json;
+use std::collections::HashSet;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// Settings shared by every run, resolved once at startup.
+pub struct Config {
+    /// A full libpq connection string or URL. When set, the `DB_*`
+    /// variables are ignored.
+    pub database_url: Option<String>,
+    pub db_host: String,
+    pub db_user: String,
+    pub db_password: String,
+    pub db_name: String,
+    /// Seconds to wait for the database before giving up.
+    pub db_connect_timeout: u64,
+    /// When false, a failed database connection falls back to `lights_file`.
+    pub require_db: bool,
+    pub lights_file: Option<String>,
+    /// With `LIGHTS_SOURCE=http`, the URL lights are loaded from instead of
+    /// the `machine` table.
+    pub lights_url: Option<String>,
+    pub lights_cache: LightsCache,
+    /// The LAN as a prefix such as `192.168.1`, or as a CIDR such as
+    /// `192.168.1.0/24`, which also bounds the lights' addresses.
+    pub network_id: String,
+    pub lat: f64,
+    pub lng: f64,
+    /// HTTP client shared by the sunrise and geocoding lookups.
+    pub http: reqwest::Client,
+    /// IANA timezone (`TIMEZONE`) passed to the sunrise API as `tzid` to
+    /// double-check the local conversion.
+    pub timezone: Option<Tz>,
+    /// With `SUNRISE_SOURCE=unix`, the Unix socket of a local service asked
+    /// for solar times before the sunrise API.
+    pub sunrise_socket: Option<String>,
+    /// Extra attempts for sunrise API failures that may be transient.
+    pub sunrise_retries: u32,
+    /// Most days `--forecast` looks up solar times for at once.
+    pub sunrise_concurrency: usize,
+    /// Shared by every run so repeated API failures stop further calls.
+    pub sunrise_breaker: CircuitBreaker,
+    /// Each day's full solar times, fetched at most once per process.
+    pub solar_cache: SolarCache,
+    /// Enables the cloudy-morning check with `WEATHER_API_KEY`
+    /// (OpenWeatherMap).
+    pub weather_api_key: Option<String>,
+    /// Cloud cover, in percent, at or above which a morning counts as cloudy.
+    pub cloudy_threshold: u8,
+    /// Minutes added to the offset of jobs turning lights off on a cloudy
+    /// morning.
+    pub cloudy_extra_minutes: i64,
+    pub weather_cache: WeatherCache,
+    /// Estimate the day's solar times from recorded history when the API
+    /// fails.
+    pub drift_fallback: bool,
+    /// Minutes from sunrise the built-in morning job uses on Saturdays and
+    /// Sundays instead of its usual offset.
+    pub weekend_offset_minutes: Option<i64>,
+    /// Plan for this day instead of today.
+    pub date: Option<NaiveDate>,
+    /// Addresses given with `--light`, used instead of the `machine` table
+    /// and `LIGHTS_FILE`.
+    pub lights: Vec<String>,
+    /// Upper bound on the lights a run will load before refusing to start.
+    pub max_lights: usize,
+    /// Fail a run that ends up with no lights, which usually means an empty
+    /// `machine` table or an over-eager filter.
+    pub error_on_empty: bool,
+    pub order_by: LightOrder,
+    /// Randomize the light order each run, so presence simulation doesn't
+    /// always switch lights in the same sequence. Replaces `order_by`.
+    pub shuffle_order: bool,
+    /// Fixed seed for `shuffle_order`, for a reproducible order.
+    pub shuffle_seed: Option<u64>,
+    /// When set, only these lights (by name) are switched.
+    pub include_lights: Option<Vec<String>>,
+    /// Lights (by name) left alone. Ignored when `include_lights` is set.
+    pub exclude_lights: Vec<String>,
+    pub bind_addr: SocketAddr,
+    /// `host:port` of a SOCKS5 proxy that UDP commands are tunnelled through
+    /// with UDP ASSOCIATE, for bulbs on a LAN not routable from here.
+    /// Discovery and state checks still go out directly.
+    pub udp_socks_proxy: Option<String>,
+    /// Kernel buffer sizes for UDP sockets, for busy networks where replies
+    /// get dropped.
+    pub socket_buffers: SocketBuffers,
+    /// With `CLOUD_FALLBACK`, the bridge base URL and token a failed local
+    /// send is retried through; see [`crate::controller::CloudController`].
+    pub cloud_fallback: Option<CloudCredentials>,
+    /// Bound on resolving a light addressed by hostname, kept apart from
+    /// delivery timeouts.
+    pub resolve_timeout: Duration,
+    /// How long discovery listens for bulbs to answer.
+    pub discovery_timeout: Duration,
+    /// At startup, check that UDP sends work on this host.
+    pub udp_self_test: bool,
+    /// At startup, wait until a light answers `getPilot` before running.
+    pub wait_for_lights: bool,
+    /// How long the startup gate waits before running anyway.
+    pub wait_for_lights_timeout: Duration,
+    /// With `KEEPALIVE_SECS`, how often a daemon sends `getPilot` to every
+    /// light to keep bulbs prone to deep sleep responsive.
+    pub keepalive: Option<Duration>,
+    /// Ask each light for its state before an off-command and leave lights
+    /// that already report off alone.
+    pub skip_if_off: bool,
+    /// Re-read each light's state after switching it.
+    pub verify_after: bool,
+    /// How long to give a bulb before re-reading its state.
+    pub confirm_delay: Duration,
+    /// Least time between a sequence or ramp step a light acknowledged and
+    /// its next step, for bulbs that ack before applying a command.
+    pub settle_delay: Duration,
+    pub morning_command: LightCommand,
+    /// Socket errors that count as a skipped light rather than a failure,
+    /// e.g. a network briefly unreachable after a VLAN flap. Empty by
+    /// default, so every error is a failure.
+    pub nonfatal_io_errors: Vec<io::ErrorKind>,
+    /// Sent once to a light whose command or sequence failed, so it isn't
+    /// left part-way through a transition.
+    pub safe_state: Option<LightCommand>,
+    pub broadcast: bool,
+    pub daemon: bool,
+    /// Switch immediately instead of waiting for the planned target.
+    pub run_now: bool,
+    /// Plan without recording anything, for `--next`: skipped lights aren't
+    /// logged and light addresses aren't stored.
+    pub preview: bool,
+    /// How long a daemon waits before its first run, e.g. for the network
+    /// to settle after boot.
+    pub startup_delay: Duration,
+    /// Extra attempts a one-shot run makes when planning or executing
+    /// fails, waiting `run_retry_delay` between them.
+    pub run_retries: u32,
+    pub run_retry_delay: Duration,
+    /// Smallest gap allowed between the start of two daemon runs.
+    pub min_run_interval: Duration,
+    /// Upper bound on the time spent sending commands once a run starts.
+    pub run_deadline: Option<Duration>,
+    /// Compute the schedule but send nothing. The `paused` row of the
+    /// `control` table can also pause a running daemon.
+    pub paused: bool,
+    /// Only record each day's solar times and targets in `observation`;
+    /// lights are never loaded or switched and runs never wait for targets.
+    pub observe_only: bool,
+    /// Failed lights a one-shot run tolerates before exiting non-zero.
+    pub fail_threshold: FailThreshold,
+    /// When set, a daemon prunes `log` rows older than this after each day.
+    pub log_retention: Option<Duration>,
+    /// Record skipped actions (filtered lights, pauses, passed targets) in
+    /// the `log` table. Errors and real actions are always recorded.
+    pub log_skips: bool,
+    pub event_log: EventLog,
+    pub console: Console,
+    /// Port of the inbound webhook a daemon serves, which lets other
+    /// automation trigger a run. Requires `webhook_secret`.
+    pub webhook_port: Option<u16>,
+    /// Shared secret inbound webhook requests must send.
+    pub webhook_secret: Option<String>,
+    /// Publishes what each run does, for any subscriber.
+    pub events: EventBus,
+    /// Told about every completed or failed run.
+    pub notifier: Arc<dyn Notifier>,
+    /// Print run reports as JSON on stdout.
+    pub json: bool,
+}
+
+impl Config {
+    /// Reads the configuration from the environment. Without `LAT`/`LNG`,
+    /// the coordinates of `CITY` are geocoded once here.
+    pub async fn from_env() -> Result<Self, AppError> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("morning-lights-off/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| AppError::InvalidConfig(format!("HTTP client: {}", e)))?;
+        let (lat, lng) = match (optional_parsed_env("LAT")?, optional_parsed_env("LNG")?) {
+            (Some(lat), Some(lng)) => (lat, lng),
+            (None, None) => match env::var("CITY") {
+                Ok(city) => geocode_city(&http, city.trim()).await?,
+                Err(_) => {
+                    return Err(AppError::InvalidConfig(
+                        "set LAT and LNG, or CITY to look them up".to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(AppError::InvalidConfig(
+                    "LAT and LNG must be set together".to_string(),
+                ))
+            }
+        };
+        let bind_addr = env::var("BIND_ADDR")
+            .unwrap_or_else(|_| "0.0.0.0:0".to_string())
+            .parse()
+            .map_err(|e| AppError::InvalidConfig(format!("BIND_ADDR: {}", e)))?;
+        let morning_command = match env::var("MORNING_COMMAND") {
+            Ok(command) => command.parse()?,
+            Err(_) => LightCommand::off(),
+        };
+
+        let network_id = required_env("NETWORK_ID")?;
+        if network_id.contains('/') {
+            network_id.parse::<ipnet::Ipv4Net>().map_err(|e| {
+                AppError::InvalidConfig(format!("NETWORK_ID {:?}: {}", network_id, e))
+            })?;
+        }
+        let webhook_port = optional_parsed_env("WEBHOOK_PORT")?;
+        let webhook_secret = env::var("WEBHOOK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+        if webhook_port.is_some() && webhook_secret.is_none() {
+            return Err(AppError::InvalidConfig(
+                "WEBHOOK_PORT requires WEBHOOK_SECRET".to_string(),
+            ));
+        }
+
+        let database_url = env::var("DATABASE_URL")
+            .ok()
+            .filter(|url| !url.trim().is_empty());
+        let db_env = |name: &str| match database_url {
+            Some(_) => Ok(env::var(name).unwrap_or_default()),
+            None => required_env(name),
+        };
+
+        Ok(Config {
+            db_host: db_env("DB_HOST")?,
+            db_user: db_env("DB_USER")?,
+            db_password: match db_password()? {
+                Some(password) => password,
+                None => db_env("DB_PASSWORD")?,
+            },
+            db_name: db_env("DB_NAME")?,
+            db_connect_timeout: optional_parsed_env("DB_CONNECT_TIMEOUT")?.unwrap_or(10),
+            database_url,
+            require_db: env_flag("REQUIRE_DB", true)?,
+            lights_file: env::var("LIGHTS_FILE").ok(),
+            lights_url: match env::var("LIGHTS_SOURCE").as_deref() {
+                Ok("http") => Some(required_env("LIGHTS_URL")?),
+                Ok("db") | Err(_) => None,
+                Ok(other) => {
+                    return Err(AppError::InvalidConfig(format!(
+                        "LIGHTS_SOURCE must be db or http, got {:?}",
+                        other
+                    )))
+                }
+            },
+            lights_cache: LightsCache::default(),
+            network_id,
+            lat,
+            lng,
+            timezone: env::var("TIMEZONE")
+                .ok()
+                .map(|name| {
+                    name.trim()
+                        .parse()
+                        .map_err(|e| AppError::InvalidConfig(format!("TIMEZONE {:?}: {}", name, e)))
+                })
+                .transpose()?,
+            sunrise_socket: match env::var("SUNRISE_SOURCE").as_deref() {
+                Ok("unix") => Some(required_env("SUNRISE_SOCKET")?),
+                Ok("http") | Err(_) => None,
+                Ok(other) => {
+                    return Err(AppError::InvalidConfig(format!(
+                        "SUNRISE_SOURCE must be http or unix, got {:?}",
+                        other
+                    )))
+                }
+            },
+            sunrise_retries: optional_parsed_env("SUNRISE_RETRIES")?.unwrap_or(2),
+            sunrise_concurrency: optional_parsed_env("SUNRISE_CONCURRENCY")?.unwrap_or(4),
+            sunrise_breaker: CircuitBreaker::new(
+                optional_parsed_env("SUNRISE_BREAKER_THRESHOLD")?.unwrap_or(3),
+                env_duration("SUNRISE_BREAKER_COOLDOWN", Duration::from_secs(60 * 60))?,
+            ),
+            solar_cache: SolarCache::default(),
+            weather_api_key: env::var("WEATHER_API_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            cloudy_threshold: optional_parsed_env("CLOUDY_THRESHOLD")?.unwrap_or(80),
+            cloudy_extra_minutes: optional_parsed_env("CLOUDY_EXTRA_MINUTES")?.unwrap_or(20),
+            weather_cache: WeatherCache::default(),
+            drift_fallback: env_flag("DRIFT_FALLBACK", false)?,
+            weekend_offset_minutes: optional_parsed_env("OFFSET_MINUTES_WEEKEND")?,
+            date: None,
+            lights: Vec::new(),
+            max_lights: optional_parsed_env("MAX_LIGHTS")?.unwrap_or(256),
+            error_on_empty: env_flag("ERROR_ON_EMPTY", true)?,
+            order_by: optional_parsed_env("ORDER_BY")?.unwrap_or_default(),
+            shuffle_order: env_flag("SHUFFLE_ORDER", false)?,
+            shuffle_seed: optional_parsed_env("SHUFFLE_SEED")?,
+            include_lights: env_list("INCLUDE_LIGHTS"),
+            exclude_lights: env_list("EXCLUDE_LIGHTS").unwrap_or_default(),
+            bind_addr,
+            udp_socks_proxy: env::var("UDP_SOCKS_PROXY").ok(),
+            socket_buffers: SocketBuffers {
+                send: optional_parsed_env("UDP_SEND_BUF")?,
+                recv: optional_parsed_env("UDP_RECV_BUF")?,
+            },
+            cloud_fallback: match env_flag("CLOUD_FALLBACK", false)? {
+                true => Some(CloudCredentials {
+                    base_url: required_env("CLOUD_API_URL")?,
+                    token: required_env("CLOUD_API_TOKEN")?,
+                }),
+                false => None,
+            },
+            resolve_timeout: Duration::from_millis(
+                optional_parsed_env("RESOLVE_TIMEOUT_MS")?.unwrap_or(2000),
+            ),
+            discovery_timeout: env_duration("DISCOVERY_TIMEOUT", Duration::from_secs(2))?,
+            udp_self_test: env_flag("UDP_SELFTEST", false)?,
+            wait_for_lights: env_flag("WAIT_FOR_LIGHTS", false)?,
+            wait_for_lights_timeout: env_duration(
+                "WAIT_FOR_LIGHTS_TIMEOUT",
+                Duration::from_secs(2 * 60),
+            )?,
+            keepalive: optional_parsed_env::<u64>("KEEPALIVE_SECS")?
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            skip_if_off: env_flag("SKIP_IF_OFF", false)?,
+            verify_after: env_flag("VERIFY_AFTER", false)?,
+            confirm_delay: Duration::from_millis(
+                optional_parsed_env("CONFIRM_DELAY_MS")?.unwrap_or(500),
+            ),
+            settle_delay: Duration::from_millis(optional_parsed_env("SETTLE_MS")?.unwrap_or(0)),
+            morning_command,
+            nonfatal_io_errors: env_list("NONFATAL_IO_ERRORS")
+                .unwrap_or_default()
+                .iter()
+                .map(|name| parse_error_kind(name))
+                .collect::<Result<_, _>>()?,
+            safe_state: env::var("SAFE_STATE")
+                .ok()
+                .map(|command| command.parse())
+                .transpose()?,
+            broadcast: false,
+            daemon: env_mode()?,
+            run_now: false,
+            preview: false,
+            startup_delay: Duration::from_secs(
+                optional_parsed_env("STARTUP_DELAY_SECS")?.unwrap_or(0),
+            ),
+            run_retries: optional_parsed_env("RUN_RETRIES")?.unwrap_or(0),
+            run_retry_delay: env_duration("RUN_RETRY_DELAY", Duration::from_secs(60))?,
+            min_run_interval: env_duration("MIN_RUN_INTERVAL", Duration::from_secs(60 * 60))?,
+            run_deadline: optional_parsed_env::<u64>("RUN_DEADLINE_SECS")?.map(Duration::from_secs),
+            paused: env_flag("PAUSED", false)?,
+            observe_only: env_flag("OBSERVE_ONLY", false)?,
+            fail_threshold: match env::var("FAIL_THRESHOLD") {
+                Ok(value) => value.parse()?,
+                Err(_) => FailThreshold::default(),
+            },
+            log_retention: optional_env_duration("LOG_RETENTION")?,
+            log_skips: env_flag("LOG_SKIPS", true)?,
+            event_log: EventLog {
+                message_max: match optional_parsed_env("LOG_MESSAGE_MAX")? {
+                    Some(0) => {
+                        return Err(AppError::InvalidConfig(
+                            "LOG_MESSAGE_MAX must be at least 1".to_string(),
+                        ))
+                    }
+                    max => max.unwrap_or(1000),
+                },
+                instance: env::var("INSTANCE_NAME")
+                    .ok()
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned()),
+                instance_column: OnceLock::new(),
+            },
+            console: Console::default(),
+            webhook_port,
+            webhook_secret,
+            events: EventBus::default(),
+            notifier: notifier_from_env(&http).into(),
+            json: false,
+            http,
+        })
+    }
+
+    /// Every setting as JSON for `--dump-config`, with the database
+    /// password, webhook secret and API keys masked.
+    pub fn redacted_json(&self) -> serde_json::Value {
+        let secret = |value: &Option<String>| value.as_ref().map(|_| REDACTED);
+        let duration = |value: Duration| humantime::format_duration(value).to_string();
+        json!({
+            "database_url": self.database_url.as_deref().map(redact_database_url),
+            "db_host": self.db_host,
+            "db_user": self.db_user,
+            "db_password": REDACTED,
+            "db_name": self.db_name,
+            "db_connect_timeout": self.db_connect_timeout,
+            "require_db": self.require_db,
+            "lights_file": self.lights_file,
+            "lights_url": self.lights_url.as_deref().map(redact_url),
+            "network_id": self.network_id,
+            "lat": self.lat,
+            "lng": self.lng,
+            "timezone": self.timezone.map(|tz| tz.name()),
+            "sunrise_socket": self.sunrise_socket,
+            "sunrise_retries": self.sunrise_retries,
+            "sunrise_concurrency": self.sunrise_concurrency,
+            "weather_api_key": secret(&self.weather_api_key),
+            "cloudy_threshold": self.cloudy_threshold,
+            "cloudy_extra_minutes": self.cloudy_extra_minutes,
+            "drift_fallback": self.drift_fallback,
+            "weekend_offset_minutes": self.weekend_offset_minutes,
+            "date": self.date.map(|date| date.to_string()),
+            "lights": self.lights,
+            "max_lights": self.max_lights,
+            "error_on_empty": self.error_on_empty,
+            "order_by": format!("{:?}", self.order_by).to_lowercase(),
+            "shuffle_order": self.shuffle_order,
+            "shuffle_seed": self.shuffle_seed,
+            "include_lights": self.include_lights,
+            "exclude_lights": self.exclude_lights,
+            "bind_addr": self.bind_addr.to_string(),
+            "udp_socks_proxy": self.udp_socks_proxy,
+            "udp_send_buf": self.socket_buffers.send,
+            "udp_recv_buf": self.socket_buffers.recv,
+            "cloud_fallback": self.cloud_fallback.as_ref().map(|cloud| json!({
+                "base_url": cloud.base_url,
+                "token": REDACTED,
+            })),
+            "resolve_timeout": duration(self.resolve_timeout),
+            "discovery_timeout": duration(self.discovery_timeout),
+            "udp_self_test": self.udp_self_test,
+            "wait_for_lights": self.wait_for_lights,
+            "wait_for_lights_timeout": duration(self.wait_for_lights_timeout),
+            "keepalive": self.keepalive.map(duration),
+            "skip_if_off": self.skip_if_off,
+            "verify_after": self.verify_after,
+            "confirm_delay": duration(self.confirm_delay),
+            "settle_delay": duration(self.settle_delay),
+            "morning_command": self.morning_command.to_string(),
+            "nonfatal_io_errors": self
+                .nonfatal_io_errors
+                .iter()
+                .map(|kind| format!("{:?}", kind))
+                .collect::<Vec<_>>(),
+            "safe_state": self.safe_state.as_ref().map(|command| command.to_string()),
+            "broadcast": self.broadcast,
+            "daemon": self.daemon,
+            "run_now": self.run_now,
+            "startup_delay": duration(self.startup_delay),
+            "run_retries": self.run_retries,
+            "run_retry_delay": duration(self.run_retry_delay),
+            "min_run_interval": duration(self.min_run_interval),
+            "run_deadline": self.run_deadline.map(duration),
+            "paused": self.paused,
+            "observe_only": self.observe_only,
+            "fail_threshold": self.fail_threshold.to_string(),
+            "log_retention": self.log_retention.map(duration),
+            "log_skips": self.log_skips,
+            "log_message_max": self.event_log.message_max,
+            "instance_name": self.event_log.instance,
+            "webhook_port": self.webhook_port,
+            "webhook_secret": secret(&self.webhook_secret),
+            "notifier": self.notifier.name(),
+            "json": self.json,
+        })
+    }
+
+    /// Every setting of [`Config::redacted_json`] with where it was
+    /// resolved from, for `--explain-config`. `process_vars` names the
+    /// variables set before `.env` was loaded and `overridden` the settings
+    /// a command-line flag replaced.
+    pub fn explain(
+        &self,
+        process_vars: &HashSet<String>,
+        overridden: &[&str],
+    ) -> Vec<ExplainedSetting> {
+        let serde_json::Value::Object(values) = self.redacted_json() else {
+            unreachable!("redacted_json returns an object");
+        };
+        SETTING_VARS
+            .iter()
+            .map(|(name, vars)| {
+                let source = match vars.iter().find(|var| env::var_os(var).is_some()) {
+                    _ if overridden.contains(name) => "command line".to_string(),
+                    Some(var) if process_vars.contains(*var) => format!("env {}", var),
+                    Some(var) => format!(".env {}", var),
+                    None => "default".to_string(),
+                };
+                ExplainedSetting {
+                    name,
+                    value: values.get(*name).cloned().unwrap_or_default(),
+                    source,
+                }
+            })
+            .collect()
+    }
+
+    /// Where UDP sockets bind and the buffer sizes they get.
+    pub fn udp_bind(&self) -> UdpBind {
+        UdpBind {
+            addr: self.bind_addr,
+            buffers: self.socket_buffers,
+        }
+    }
+
+    pub fn connection_string(&self) -> String {
+        if let Some(url) = &self.database_url {
+            return url.clone();
+        }
+        format!(
+            "host={} user={} password={} dbname={} application_name=morning-lights-off connect_timeout={}",
+            self.db_host, self.db_user, self.db_password, self.db_name, self.db_connect_timeout
+        )
+    }
+}
+
+/// The database password from `DB_PASSWORD`, `DB_PASSWORD_FILE` or
+/// `DB_PASSWORD_ENC` with `DB_KEY_FILE`, in that order of precedence.
+fn db_password() -> Result<Option<String>, AppError> {
+    if let Ok(password) = env::var("DB_PASSWORD") {
+        return Ok(Some(password));
+    }
+    if let Ok(path) = env::var("DB_PASSWORD_FILE") {
+        let password = std::fs::read_to_string(&path).map_err(|e| {
+            AppError::InvalidConfig(format!("cannot read DB_PASSWORD_FILE {}: {}", path, e))
+        })?;
+        return Ok(Some(password.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    if let Ok(encrypted) = env::var("DB_PASSWORD_ENC") {
+        let key_path = required_env("DB_KEY_FILE")?;
+        return decrypt_password(&encrypted, &key_path).map(Some);
+    }
+    Ok(None)
+}
+
+/// Where `CLOUD_FALLBACK` sends commands.
+pub struct CloudCredentials {
+    /// `CLOUD_API_URL`.
+    pub base_url: String,
+    /// `CLOUD_API_TOKEN`.
+    pub token: String,
+}
+
+/// One line of `--explain-config`.
+pub struct ExplainedSetting {
+    pub name: &'static str,
+    /// As in `--dump-config`, secrets masked.
+    pub value: serde_json::Value,
+    /// `env VAR`, `.env VAR`, `command line` or `default`.
+    pub source: String,
+}
+
+/// The variables each [`Config::redacted_json`] key is read from, the one
+/// that wins first. Empty for settings only a flag sets.
+const SETTING_VARS: &[(&str, &[&str])] = &[
+    ("database_url", &["DATABASE_URL"]),
+    ("db_host", &["DB_HOST"]),
+    ("db_user", &["DB_USER"]),
+    (
+        "db_password",
+        &["DB_PASSWORD", "DB_PASSWORD_FILE", "DB_PASSWORD_ENC"],
+    ),
+    ("db_name", &["DB_NAME"]),
+    ("db_connect_timeout", &["DB_CONNECT_TIMEOUT"]),
+    ("require_db", &["REQUIRE_DB"]),
+    ("lights_file", &["LIGHTS_FILE"]),
+    ("lights_url", &["LIGHTS_SOURCE", "LIGHTS_URL"]),
+    ("network_id", &["NETWORK_ID"]),
+    ("lat", &["LAT", "CITY"]),
+    ("lng", &["LNG", "CITY"]),
+    ("timezone", &["TIMEZONE"]),
+    ("sunrise_socket", &["SUNRISE_SOURCE", "SUNRISE_SOCKET"]),
+    ("sunrise_retries", &["SUNRISE_RETRIES"]),
+    ("sunrise_concurrency", &["SUNRISE_CONCURRENCY"]),
+    ("weather_api_key", &["WEATHER_API_KEY"]),
+    ("cloudy_threshold", &["CLOUDY_THRESHOLD"]),
+    ("cloudy_extra_minutes", &["CLOUDY_EXTRA_MINUTES"]),
+    ("drift_fallback", &["DRIFT_FALLBACK"]),
+    ("weekend_offset_minutes", &["OFFSET_MINUTES_WEEKEND"]),
+    ("date", &[]),
+    ("lights", &[]),
+    ("max_lights", &["MAX_LIGHTS"]),
+    ("error_on_empty", &["ERROR_ON_EMPTY"]),
+    ("order_by", &["ORDER_BY"]),
+    ("shuffle_order", &["SHUFFLE_ORDER"]),
+    ("shuffle_seed", &["SHUFFLE_SEED"]),
+    ("include_lights", &["INCLUDE_LIGHTS"]),
+    ("exclude_lights", &["EXCLUDE_LIGHTS"]),
+    ("bind_addr", &["BIND_ADDR"]),
+    ("udp_socks_proxy", &["UDP_SOCKS_PROXY"]),
+    ("udp_send_buf", &["UDP_SEND_BUF"]),
+    ("udp_recv_buf", &["UDP_RECV_BUF"]),
+    ("cloud_fallback", &["CLOUD_FALLBACK"]),
+    ("resolve_timeout", &["RESOLVE_TIMEOUT_MS"]),
+    ("discovery_timeout", &["DISCOVERY_TIMEOUT"]),
+    ("udp_self_test", &["UDP_SELFTEST"]),
+    ("wait_for_lights", &["WAIT_FOR_LIGHTS"]),
+    ("wait_for_lights_timeout", &["WAIT_FOR_LIGHTS_TIMEOUT"]),
+    ("keepalive", &["KEEPALIVE_SECS"]),
+    ("skip_if_off", &["SKIP_IF_OFF"]),
+    ("verify_after", &["VERIFY_AFTER"]),
+    ("confirm_delay", &["CONFIRM_DELAY_MS"]),
+    ("settle_delay", &["SETTLE_MS"]),
+    ("morning_command", &["MORNING_COMMAND"]),
+    ("nonfatal_io_errors", &["NONFATAL_IO_ERRORS"]),
+    ("safe_state", &["SAFE_STATE"]),
+    ("broadcast", &[]),
+    ("daemon", &["MODE"]),
+    ("run_now", &[]),
+    ("startup_delay", &["STARTUP_DELAY_SECS"]),
+    ("run_retries", &["RUN_RETRIES"]),
+    ("run_retry_delay", &["RUN_RETRY_DELAY"]),
+    ("min_run_interval", &["MIN_RUN_INTERVAL"]),
+    ("run_deadline", &["RUN_DEADLINE_SECS"]),
+    ("paused", &["PAUSED"]),
+    ("observe_only", &["OBSERVE_ONLY"]),
+    ("fail_threshold", &["FAIL_THRESHOLD"]),
+    ("log_retention", &["LOG_RETENTION"]),
+    ("log_skips", &["LOG_SKIPS"]),
+    ("log_message_max", &["LOG_MESSAGE_MAX"]),
+    ("instance_name", &["INSTANCE_NAME"]),
+    ("webhook_port", &["WEBHOOK_PORT"]),
+    ("webhook_secret", &["WEBHOOK_SECRET"]),
+    ("notifier", &["WEBHOOK_URL"]),
+    ("json", &[]),
+];
+
+/// Stands in for a secret in `--dump-config` output.
+const REDACTED: &str = "***";
+
+/// Keeps only the scheme and host of a URL, since webhook URLs such as
+/// Slack's and Discord's carry their secret in the path.
+pub(crate) fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => {
+                format!("{}://{}:{}/{}", parsed.scheme(), host, port, REDACTED)
+            }
+            (Some(host), None) => format!("{}://{}/{}", parsed.scheme(), host, REDACTED),
+            (None, _) => REDACTED.to_string(),
+        },
+        Err(_) => REDACTED.to_string(),
+    }
+}
+
+/// Masks the password in a connection URL or key/value string.
+fn redact_database_url(url: &str) -> String {
+    if let Ok(mut parsed) = reqwest::Url::parse(url) {
+        if parsed.password().is_some() {
+            let _ = parsed.set_password(Some(REDACTED));
+        }
+        // libpq URLs may also pass it as a query parameter
+        let query: Vec<(String, String)> = parsed
+            .query_pairs()
+            .map(|(key, value)| match key.as_ref() {
+                "password" => (key.into_owned(), REDACTED.to_string()),
+                _ => (key.into_owned(), value.into_owned()),
+            })
+            .collect();
+        if !query.is_empty() {
+            parsed.query_pairs_mut().clear().extend_pairs(query);
+        }
+        return parsed.to_string();
+    }
+    url.split_whitespace()
+        .map(|pair| match pair.split_once('=') {
+            Some(("password", _)) => format!("password={}", REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn required_env(name: &str) -> Result<String, AppError> {
+    env::var(name).map_err(|_| AppError::InvalidConfig(format!("{} not set", name)))
+}
+
+fn parsed_env<T: std::str::FromStr>(name: &str) -> Result<T, AppError> {
+    let value = required_env(name)?;
+    value
+        .trim()
+        .parse()
+        .map_err(|_| AppError::InvalidConfig(format!("invalid {} value {:?}", name, value)))
+}
+
+fn optional_parsed_env<T: std::str::FromStr>(name: &str) -> Result<Option<T>, AppError> {
+    match env::var(name) {
+        Ok(_) => parsed_env(name).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// A comma-separated list, or `None` when the variable is unset or empty.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let items: Vec<String> = env::var(name)
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!items.is_empty()).then_some(items)
+}
+
+/// An `io::ErrorKind` by its snake_case name, e.g. `network_unreachable`.
+fn parse_error_kind(name: &str) -> Result<io::ErrorKind, AppError> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "network_unreachable" => io::ErrorKind::NetworkUnreachable,
+        "network_down" => io::ErrorKind::NetworkDown,
+        "host_unreachable" => io::ErrorKind::HostUnreachable,
+        "connection_refused" => io::ErrorKind::ConnectionRefused,
+        "connection_reset" => io::ErrorKind::ConnectionReset,
+        "connection_aborted" => io::ErrorKind::ConnectionAborted,
+        "addr_not_available" => io::ErrorKind::AddrNotAvailable,
+        "timed_out" => io::ErrorKind::TimedOut,
+        "permission_denied" => io::ErrorKind::PermissionDenied,
+        _ => {
+            return Err(AppError::InvalidConfig(format!(
+                "NONFATAL_IO_ERRORS: unknown error kind {:?}",
+                name
+            )))
+        }
+    })
+}
+
+fn env_duration(name: &str, default: Duration) -> Result<Duration, AppError> {
+    Ok(optional_env_duration(name)?.unwrap_or(default))
+}
+
+fn optional_env_duration(name: &str) -> Result<Option<Duration>, AppError> {
+    match env::var(name) {
+        Ok(value) => humantime::parse_duration(value.trim())
+            .map(Some)
+            .map_err(|e| {
+                AppError::InvalidConfig(format!(
+                    "{} must be a duration such as \"1h\" or \"90s\", got {:?}: {}",
+                    name, value, e
+                ))
+            }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// `MODE`: `oneshot` (the default) runs once and exits, `daemon` keeps
+/// running.
+fn env_mode() -> Result<bool, AppError> {
+    match env::var("MODE") {
+        Ok(mode) => match mode.trim().to_ascii_lowercase().as_str() {
+            "oneshot" | "one-shot" => Ok(false),
+            "daemon" => Ok(true),
+            _ => Err(AppError::InvalidConfig(format!(
+                "MODE must be oneshot or daemon, got {:?}",
+                mode
+            ))),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+fn env_flag(name: &str, default: bool) -> Result<bool, AppError> {
+    match env::var(name) {
+        Ok(value) => parse_flag(&value).ok_or_else(|| {
+            AppError::InvalidConfig(format!("{} must be true or false, got {:?}", name, value))
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parses the boolean spellings accepted in env vars and control rows.
+pub(crate) fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// A configuration with only the required settings, as a one-shot run
+    /// without a database. Tests adjust the fields they exercise.
+    pub(crate) async fn for_tests() -> Config {
+        static ENV: std::sync::Once = std::sync::Once::new();
+        ENV.call_once(|| {
+            env::set_var("LAT", "51.5");
+            env::set_var("LNG", "-0.12");
+            env::set_var("NETWORK_ID", "192.168.1.0/24");
+            env::set_var("DATABASE_URL", "postgres://localhost/lights");
+        });
+        Config::from_env().await.expect("test configuration")
+    }
+}
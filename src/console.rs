@@ -0,0 +1,104 @@
+use crate::error::AppError;
+use std::io::IsTerminal;
+use std::str::FromStr;
+
+/// When console output is colorized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(AppError::InvalidConfig(format!(
+                "color must be auto, always or never, got {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Human-readable progress output. Database logging is separate and never
+/// affected by these settings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Console {
+    quiet: bool,
+    color: bool,
+    /// Keeps stdout free for machine-readable output such as `--json`.
+    stderr: bool,
+}
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+impl Console {
+    /// `Auto` colors only when the output is a terminal and `NO_COLOR` is
+    /// unset. With `stderr`, everything is written to stderr.
+    pub fn new(quiet: bool, color: ColorMode, stderr: bool) -> Self {
+        let color = match color {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => {
+                let terminal = if stderr {
+                    std::io::stderr().is_terminal()
+                } else {
+                    std::io::stdout().is_terminal()
+                };
+                terminal && std::env::var_os("NO_COLOR").is_none()
+            }
+        };
+        Console {
+            quiet,
+            color,
+            stderr,
+        }
+    }
+
+    pub fn info(&self, message: &str) {
+        if !self.quiet {
+            self.print(message);
+        }
+    }
+
+    pub fn success(&self, message: &str) {
+        if !self.quiet {
+            self.print(&self.paint(GREEN, "SUCCESS:", message));
+        }
+    }
+
+    pub fn warn(&self, message: &str) {
+        eprintln!("{}", self.paint(YELLOW, "WARNING:", message));
+    }
+
+    /// Errors are printed even in quiet mode.
+    pub fn error(&self, message: &str) {
+        self.print(&self.paint(RED, "ERROR:", message));
+    }
+
+    fn print(&self, line: &str) {
+        if self.stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    fn paint(&self, color: &str, label: &str, message: &str) -> String {
+        if self.color {
+            format!("{}{} {}{}", color, label, message, RESET)
+        } else {
+            format!("{} {}", label, message)
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::error::AppError;
+use crate::light::{check_ack, WizLight};
+use crate::transport::{
+    query_udp, query_udp_socks, resolve, send_tcp_packet, send_udp_packet, send_udp_socks, UdpBind,
+};
+use async_trait::async_trait;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How a command reaches a light.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Fire-and-forget datagram, understood by every WiZ bulb.
+    #[default]
+    Udp,
+    /// Connection with a reply, for firmware that supports it.
+    Tcp,
+}
+
+impl FromStr for Transport {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            _ => Err(AppError::InvalidTransport(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        })
+    }
+}
+
+/// Delivers a payload to a light.
+#[async_trait]
+pub trait LightController: Send + Sync {
+    async fn send(&self, light: &WizLight, payload: &str) -> Result<(), AppError>;
+}
+
+/// How long an acknowledged UDP send waits for the bulb's reply.
+const UDP_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Talks to lights directly on the LAN using each light's transport.
+pub struct LocalController {
+    bind: UdpBind,
+    /// SOCKS5 proxy that UDP sends are tunnelled through.
+    socks_proxy: Option<String>,
+    /// Bound on resolving a light addressed by hostname.
+    resolve_timeout: Duration,
+}
+
+/// Resolution bound used unless [`LocalController::with_resolve_timeout`]
+/// sets another.
+const DEFAULT_RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl LocalController {
+    pub fn new(bind: UdpBind) -> Self {
+        LocalController {
+            bind,
+            socks_proxy: None,
+            resolve_timeout: DEFAULT_RESOLVE_TIMEOUT,
+        }
+    }
+
+    pub fn with_resolve_timeout(mut self, resolve_timeout: Duration) -> Self {
+        self.resolve_timeout = resolve_timeout;
+        self
+    }
+
+    /// Tunnels UDP commands through the SOCKS5 proxy at `proxy`, which must
+    /// support UDP ASSOCIATE. TCP lights are still reached directly.
+    pub fn with_socks_proxy(mut self, proxy: Option<String>) -> Self {
+        self.socks_proxy = proxy;
+        self
+    }
+}
+
+#[async_trait]
+impl LightController for LocalController {
+    async fn send(&self, light: &WizLight, payload: &str) -> Result<(), AppError> {
+        let proxy = self.socks_proxy.as_deref();
+        let addr = resolve(&light.host_id, self.resolve_timeout).await?;
+        match (light.transport, light.require_ack) {
+            (Transport::Udp, false) => match proxy {
+                Some(proxy) => send_udp_socks(proxy, self.bind, addr, payload).await,
+                None => send_udp_packet(self.bind, addr, payload).await,
+            },
+            (Transport::Udp, true) => {
+                let reply = match proxy {
+                    Some(proxy) => {
+                        query_udp_socks(proxy, self.bind, addr, payload, UDP_ACK_TIMEOUT).await?
+                    }
+                    None => query_udp(self.bind, addr, payload, UDP_ACK_TIMEOUT).await?,
+                };
+                check_ack(&reply)
+            }
+            (Transport::Tcp, require_ack) => send_tcp_packet(addr, payload, require_ack).await,
+        }
+    }
+}
+
+/// Sends commands through a cloud bridge over HTTPS, for bulbs the LAN
+/// can't reach. WiZ publishes no cloud API, so the bridge at `base_url` is
+/// expected to forward a `setPilot` request POSTed to
+/// `{base_url}/lights/{mac}/pilot`, authorized with `token` as a bearer
+/// token. Bulbs are identified by MAC, so lights without one can't use it.
+pub struct CloudController {
+    http: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl CloudController {
+    pub fn new(http: reqwest::Client, base_url: String, token: String) -> Self {
+        CloudController {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+}
+
+#[async_trait]
+impl LightController for CloudController {
+    async fn send(&self, light: &WizLight, payload: &str) -> Result<(), AppError> {
+        let failed = |reason: String| AppError::Cloud {
+            name: light.name.clone(),
+            reason,
+        };
+        let mac = light
+            .mac
+            .as_deref()
+            .ok_or_else(|| failed("the light has no mac".to_string()))?;
+        self.http
+            .post(format!("{}/lights/{}/pilot", self.base_url, mac))
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| failed(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Tries `primary`, then `fallback` when it fails. The fallback is the
+/// cloud bridge, which addresses bulbs by MAC, so a light without one only
+/// gets the primary attempt. The error returned is always the primary's,
+/// which keeps the real socket error and lets it count as nonfatal.
+pub struct FallbackController<P, F> {
+    primary: P,
+    fallback: F,
+}
+
+impl<P, F> FallbackController<P, F> {
+    pub fn new(primary: P, fallback: F) -> Self {
+        FallbackController { primary, fallback }
+    }
+}
+
+#[async_trait]
+impl<P: LightController, F: LightController> LightController for FallbackController<P, F> {
+    async fn send(&self, light: &WizLight, payload: &str) -> Result<(), AppError> {
+        let error = match self.primary.send(light, payload).await {
+            Ok(()) => return Ok(()),
+            Err(e) if light.mac.is_none() => return Err(e),
+            Err(e) => e,
+        };
+        tracing::warn!(light = %light.name, error = %error, "local send failed; trying the cloud fallback");
+        match self.fallback.send(light, payload).await {
+            Ok(()) => Ok(()),
+            Err(fallback_error) => {
+                tracing::warn!(light = %light.name, error = %fallback_error, "cloud fallback failed");
+                Err(error)
+            }
+        }
+    }
+}
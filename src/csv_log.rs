@@ -0,0 +1,134 @@
+use crate::db::EVENT_LOG_TARGET;
+use chrono::Utc;
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+const HEADER: &str = "timestamp,severity,machine,event_type,message,run_id\n";
+
+/// Appends every logged event (see [`EVENT_LOG_TARGET`]) to `CSV_LOG` as a
+/// spreadsheet-friendly row. `run_id` is the `run_at` of the run that sent
+/// the event, matching `run_result`, and empty outside a run.
+pub struct CsvLayer {
+    file: Mutex<File>,
+}
+
+impl CsvLayer {
+    /// Opens `path` for appending, writing the header row to a new file.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(HEADER.as_bytes())?;
+        }
+        Ok(CsvLayer {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+/// The `run_id` field of a `run` span, kept in the span's extensions.
+struct RunId(String);
+
+impl<S> Layer<S> for CsvLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let (Some(run_id), Some(span)) = (fields.run_id, ctx.span(id)) {
+            span.extensions_mut().insert(RunId(run_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != EVENT_LOG_TARGET {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let run_id = ctx
+            .event_scope(event)
+            .and_then(|scope| {
+                scope
+                    .into_iter()
+                    .find_map(|span| span.extensions().get::<RunId>().map(|id| id.0.clone()))
+            })
+            .unwrap_or_default();
+        let severity = match *event.metadata().level() {
+            Level::ERROR => "Error",
+            Level::WARN => "Warn",
+            _ => "Info",
+        };
+
+        let mut row = String::new();
+        let columns = [
+            Utc::now().to_rfc3339(),
+            severity.to_string(),
+            fields.machine.unwrap_or_default(),
+            fields.event_type.unwrap_or_default(),
+            fields.message.unwrap_or_default(),
+            run_id,
+        ];
+        for (index, column) in columns.iter().enumerate() {
+            if index > 0 {
+                row.push(',');
+            }
+            push_csv_field(&mut row, column);
+        }
+        row.push('\n');
+        // A failed write can't be logged without recursing into this layer
+        let _ = self.file.lock().unwrap().write_all(row.as_bytes());
+    }
+}
+
+/// Quotes a field when it holds a comma, quote or line break, doubling any
+/// quotes inside.
+fn push_csv_field(row: &mut String, value: &str) {
+    if !value.contains([',', '"', '\n', '\r']) {
+        row.push_str(value);
+        return;
+    }
+    row.push('"');
+    row.push_str(&value.replace('"', "\"\""));
+    row.push('"');
+}
+
+#[derive(Default)]
+struct Fields {
+    machine: Option<String>,
+    event_type: Option<String>,
+    message: Option<String>,
+    run_id: Option<String>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut text = String::new();
+        let _ = write!(text, "{:?}", value);
+        self.set(field, text);
+    }
+}
+
+impl Fields {
+    fn set(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "machine" => self.machine = Some(value),
+            "event_type" => self.event_type = Some(value),
+            "message" => self.message = Some(value),
+            "run_id" => self.run_id = Some(value),
+            _ => {}
+        }
+    }
+}
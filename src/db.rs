@@ -0,0 +1,572 @@
+use crate::discovery::DiscoveredLight;
+use crate::error::AppError;
+use crate::light::LightCommand;
+use crate::plan::{Forecast, RunReport};
+use crate::sunrise::SolarTimes;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+/// Loads the solar times recorded for `day`, with the day length taken as
+/// sunrise to sunset. Rows written before sunset, civil twilight and solar
+/// noon were stored count as missing, as is everything without the
+/// `daily_solar` table (see `migrations/daily_solar.sql`).
+pub async fn load_daily_solar(
+    client: &Client,
+    day: NaiveDate,
+) -> Result<Option<SolarTimes>, AppError> {
+    let row = match client
+        .query_opt(
+            "SELECT sunrise, sunset, civil_twilight_begin, solar_noon FROM daily_solar \
+             WHERE day = $1",
+            &[&day],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(row.and_then(|row| {
+        let sunset: Option<DateTime<Utc>> = row.get("sunset");
+        let civil_twilight_begin: Option<DateTime<Utc>> = row.get("civil_twilight_begin");
+        let solar_noon: Option<DateTime<Utc>> = row.get("solar_noon");
+        let sunrise: DateTime<Utc> = row.get("sunrise");
+        Some(SolarTimes {
+            sunrise,
+            sunset: sunset?,
+            civil_twilight_begin: civil_twilight_begin?,
+            solar_noon: solar_noon?,
+            day_length: sunset? - sunrise,
+        })
+    }))
+}
+
+/// Records `day`'s solar times in `daily_solar`; a no-op without the table.
+pub async fn store_daily_solar(
+    client: &Client,
+    day: NaiveDate,
+    times: &SolarTimes,
+) -> Result<(), AppError> {
+    let result = client
+        .execute(
+            "INSERT INTO daily_solar (day, sunrise, sunset, civil_twilight_begin, solar_noon) \
+             VALUES ($1, $2, $3, $4, $5) \
+             ON CONFLICT (day) DO UPDATE SET sunrise = EXCLUDED.sunrise, \
+             sunset = EXCLUDED.sunset, civil_twilight_begin = EXCLUDED.civil_twilight_begin, \
+             solar_noon = EXCLUDED.solar_noon",
+            &[
+                &day,
+                &times.sunrise,
+                &times.sunset,
+                &times.civil_twilight_begin,
+                &times.solar_noon,
+            ],
+        )
+        .await;
+    match result {
+        Err(e) if e.code() != Some(&SqlState::UNDEFINED_TABLE) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The most recent complete `daily_solar` rows before `day`, newest first.
+/// Empty without the table.
+pub async fn load_recent_solar(
+    client: &Client,
+    day: NaiveDate,
+    limit: i64,
+) -> Result<Vec<(NaiveDate, SolarTimes)>, AppError> {
+    let rows = match client
+        .query(
+            "SELECT day, sunrise, sunset, civil_twilight_begin, solar_noon FROM daily_solar \
+             WHERE day < $1 AND sunset IS NOT NULL AND civil_twilight_begin IS NOT NULL \
+             AND solar_noon IS NOT NULL ORDER BY day DESC LIMIT $2",
+            &[&day, &limit],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let sunrise: DateTime<Utc> = row.get("sunrise");
+            let sunset: DateTime<Utc> = row.get("sunset");
+            (
+                row.get("day"),
+                SolarTimes {
+                    sunrise,
+                    sunset,
+                    civil_twilight_begin: row.get("civil_twilight_begin"),
+                    solar_noon: row.get("solar_noon"),
+                    day_length: sunset - sunrise,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Inserts a discovered bulb into `machine`, keyed on the unique `mac`
+/// column. An existing row is left alone except that an empty name is
+/// filled in. Returns true when a new row was added.
+pub async fn save_discovered_light(
+    client: &Client,
+    light: &DiscoveredLight,
+) -> Result<bool, AppError> {
+    let host_id = light.addr.ip().to_string();
+    let name = format!("wiz-{}", light.mac);
+    let row = client
+        .query_opt(
+            "INSERT INTO machine (host_id, name, mac) VALUES ($1, $2, $3) \
+             ON CONFLICT (mac) DO UPDATE SET name = EXCLUDED.name \
+             WHERE machine.name IS NULL OR machine.name = '' \
+             RETURNING (xmax = 0) AS inserted",
+            &[&host_id, &name, &light.mac],
+        )
+        .await?;
+
+    Ok(row.is_some_and(|row| row.get("inserted")))
+}
+
+/// Stores the address a light resolved to this run and returns the one
+/// recorded by the previous run, if any. See `migrations/light_address.sql`.
+pub async fn record_light_address(
+    client: &Client,
+    name: &str,
+    addr: &str,
+) -> Result<Option<String>, AppError> {
+    let row = client
+        .query_one(
+            "WITH previous AS (SELECT addr FROM light_address WHERE name = $1) \
+             INSERT INTO light_address (name, addr, seen_at) VALUES ($1, $2, now()) \
+             ON CONFLICT (name) DO UPDATE SET addr = EXCLUDED.addr, seen_at = EXCLUDED.seen_at \
+             RETURNING (SELECT addr FROM previous) AS previous",
+            &[&name, &addr],
+        )
+        .await?;
+
+    Ok(row.get("previous"))
+}
+
+/// Records each light's outcome in `run_result` (see
+/// `migrations/run_result.sql`), grouped by `run_at`.
+pub async fn store_run_results(
+    client: &Client,
+    run_at: DateTime<Utc>,
+    report: &RunReport,
+) -> Result<(), AppError> {
+    for result in &report.results {
+        let command =
+            serde_json::to_string(&result.command).expect("light commands serialize to JSON");
+        let latency_ms = result.latency.as_secs_f64() * 1000.0;
+        client
+            .execute(
+                "INSERT INTO run_result \
+                 (run_at, job, name, host_id, command, status, error, latency_ms) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &run_at,
+                    &report.job,
+                    &result.name,
+                    &result.host_id,
+                    &command,
+                    &result.outcome.status(),
+                    &result.outcome.error(),
+                    &latency_ms,
+                ],
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Records a job's computed times for a day in `observation`, replacing any
+/// earlier row for the same day and job. See `migrations/observation.sql`.
+pub async fn store_observation(client: &Client, forecast: &Forecast) -> Result<(), AppError> {
+    client
+        .execute(
+            "INSERT INTO observation (day, job, event, event_time, target, observed_at) \
+             VALUES ($1, $2, $3, $4, $5, now()) \
+             ON CONFLICT (day, job) DO UPDATE SET event = EXCLUDED.event, \
+             event_time = EXCLUDED.event_time, target = EXCLUDED.target, \
+             observed_at = EXCLUDED.observed_at",
+            &[
+                &forecast.day,
+                &forecast.job,
+                &forecast.event.to_string(),
+                &forecast.event_time.to_utc(),
+                &forecast.target.to_utc(),
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Records one `run_summary` row for a finished run, keyed by its `run_at`:
+/// light counts, the solar event and target times, and how long the run
+/// took. Skipped runs have no `run_at` and are not recorded. See
+/// `migrations/run_summary.sql`.
+pub async fn log_run_summary(
+    client: &Client,
+    report: &RunReport,
+    event_time: DateTime<Utc>,
+    target: DateTime<Utc>,
+    duration: Duration,
+) -> Result<(), AppError> {
+    let Some(run_at) = report.run_at else {
+        return Ok(());
+    };
+    let total = report.results.len() as i32;
+    let failed = report.failures() as i32;
+    let duration_ms = duration.as_secs_f64() * 1000.0;
+    client
+        .execute(
+            "INSERT INTO run_summary \
+             (run_at, job, total, succeeded, failed, event_time, target, duration_ms) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+             ON CONFLICT (run_at) DO NOTHING",
+            &[
+                &run_at,
+                &report.job,
+                &total,
+                &(total - failed),
+                &failed,
+                &event_time,
+                &target,
+                &duration_ms,
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// The lights that failed or timed out in the most recent recorded run,
+/// with the command each was sent.
+pub async fn load_last_run_failures(
+    client: &Client,
+) -> Result<Vec<(String, LightCommand)>, AppError> {
+    let rows = client
+        .query(
+            "SELECT name, command FROM run_result \
+             WHERE run_at = (SELECT max(run_at) FROM run_result) \
+             AND status IN ('failed', 'timed_out') ORDER BY name",
+            &[],
+        )
+        .await?;
+
+    rows.iter()
+        .map(|row| {
+            let name: String = row.get("name");
+            let command: String = row.get("command");
+            let command = serde_json::from_str(&command)
+                .map_err(|e| AppError::InvalidCommand(format!("recorded for {}: {}", name, e)))?;
+            Ok((name, command))
+        })
+        .collect()
+}
+
+/// Reads a value from the `control` table, which holds runtime switches
+/// that take effect without a restart (see `migrations/control.sql`). A
+/// missing table is the same as an unset key.
+pub async fn load_control(client: &Client, key: &str) -> Result<Option<String>, AppError> {
+    let row = match client
+        .query_opt("SELECT value FROM control WHERE key = $1", &[&key])
+        .await
+    {
+        Ok(row) => row,
+        Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(row.map(|row| row.get("value")))
+}
+
+/// Removes a `control` row and returns its value, for switches that apply
+/// only once. A missing table is the same as an unset key.
+pub async fn take_control(client: &Client, key: &str) -> Result<Option<String>, AppError> {
+    let row = match client
+        .query_opt(
+            "DELETE FROM control WHERE key = $1 RETURNING value",
+            &[&key],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(row.map(|row| row.get("value")))
+}
+
+/// Rows removed per statement when pruning, so a large backlog doesn't
+/// hold one long-running lock.
+const PRUNE_BATCH: i64 = 10_000;
+
+/// Deletes `log` rows whose `created_at` is older than `older_than`, in
+/// batches. Returns the number of rows removed.
+pub async fn prune_logs(client: &Client, older_than: Duration) -> Result<u64, AppError> {
+    let cutoff = Utc::now()
+        - chrono::Duration::from_std(older_than)
+            .map_err(|e| AppError::InvalidConfig(format!("retention {:?}: {}", older_than, e)))?;
+    let mut removed = 0;
+    loop {
+        let deleted = client
+            .execute(
+                "DELETE FROM log WHERE ctid IN \
+                 (SELECT ctid FROM log WHERE created_at < $1 LIMIT $2)",
+                &[&cutoff, &PRUNE_BATCH],
+            )
+            .await?;
+        removed += deleted;
+        if deleted < PRUNE_BATCH as u64 {
+            return Ok(removed);
+        }
+    }
+}
+
+/// Whether the connection still answers a trivial query, e.g. after a
+/// one-shot run slept for hours until its target.
+pub async fn is_alive(client: &Client) -> bool {
+    !client.is_closed() && client.simple_query("SELECT 1").await.is_ok()
+}
+
+/// Column types the code reads a text value from.
+const TEXT_TYPES: &[&str] = &["text", "character varying", "character"];
+
+/// The `machine` and `log` columns the queries rely on, with the
+/// `information_schema` types each may have.
+const EXPECTED_COLUMNS: &[(&str, &str, &[&str])] = &[
+    ("machine", "host_id", TEXT_TYPES),
+    ("machine", "name", TEXT_TYPES),
+    ("machine", "action", TEXT_TYPES),
+    ("machine", "transport", TEXT_TYPES),
+    ("machine", "network_id", TEXT_TYPES),
+    ("machine", "mac", TEXT_TYPES),
+    ("machine", "sequence", &["json", "jsonb"]),
+    ("machine", "sort_order", &["integer"]),
+    ("machine", "require_ack", &["boolean"]),
+    ("machine", "room", TEXT_TYPES),
+    ("machine", "firmware_profile", TEXT_TYPES),
+    ("log", "severity", TEXT_TYPES),
+    ("log", "message", TEXT_TYPES),
+    ("log", "machine", TEXT_TYPES),
+    ("log", "event_type", TEXT_TYPES),
+    ("log", "instance", TEXT_TYPES),
+    ("log", "created_at", &["timestamp with time zone"]),
+];
+
+/// Compares the `machine` and `log` tables in the current schema with
+/// [`EXPECTED_COLUMNS`] and describes every missing table or column and
+/// every column of the wrong type. Empty when the schema matches.
+pub async fn check_schema(client: &Client) -> Result<Vec<String>, AppError> {
+    let rows = client
+        .query(
+            "SELECT table_name::text, column_name::text, data_type::text \
+             FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name IN ('machine', 'log')",
+            &[],
+        )
+        .await?;
+    let found: Vec<(String, String, String)> = rows
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+
+    let mut mismatches = Vec::new();
+    for table in ["machine", "log"] {
+        if !found.iter().any(|(name, _, _)| name == table) {
+            mismatches.push(format!("table {} is missing", table));
+        }
+    }
+    for (table, column, types) in EXPECTED_COLUMNS {
+        if !found.iter().any(|(name, _, _)| name == table) {
+            continue;
+        }
+        match found
+            .iter()
+            .find(|(name, col, _)| name == table && col == column)
+        {
+            None => mismatches.push(format!("{}.{} is missing", table, column)),
+            Some((_, _, data_type)) if !types.contains(&data_type.as_str()) => {
+                mismatches.push(format!(
+                    "{}.{} is {}, expected {}",
+                    table,
+                    column,
+                    data_type,
+                    types.join(" or ")
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(mismatches)
+}
+
+/// `tracing` target of the events recorded by [`log_event`], which
+/// `LOG_FILE` copies.
+pub const EVENT_LOG_TARGET: &str = "morning_lights_off::event_log";
+
+/// Records a morning-routine event. See [`log_event`].
+pub async fn log_light_event(
+    log: &EventLog,
+    client: Option<&Client>,
+    severity: &str,
+    message: &str,
+    machine: &str,
+) -> Result<(), AppError> {
+    log_event(log, client, "Morning", severity, message, machine).await
+}
+
+/// How [`log_event`] writes `log` rows, resolved once by
+/// [`crate::Config::from_env`].
+#[derive(Clone, Debug)]
+pub struct EventLog {
+    /// Longest `log.message` inserted, in characters: `LOG_MESSAGE_MAX`,
+    /// which should match the column's `varchar` length, or 1000.
+    pub message_max: usize,
+    /// Names this deployment in the `instance` column, so several sharing
+    /// a database can be told apart: `INSTANCE_NAME`, or else the system
+    /// hostname.
+    pub instance: String,
+    /// Set to false once an insert finds the `log` table without the
+    /// `instance` column (see `migrations/log_instance.sql`), after which
+    /// rows are written without it.
+    pub(crate) instance_column: OnceLock<bool>,
+}
+
+impl EventLog {
+    /// Whether the next insert should try the `instance` column.
+    fn writes_instance(&self) -> bool {
+        self.instance_column.get() != Some(&false)
+    }
+
+    /// Records that the `instance` column is missing. Concurrent inserts
+    /// can all find it missing; only the first caller gets true, so the
+    /// warning is logged once.
+    fn mark_instance_missing(&self) -> bool {
+        self.instance_column.set(false).is_ok()
+    }
+}
+
+/// Records an event in the `log` table, or on stderr when running without a
+/// database, and emits it under [`EVENT_LOG_TARGET`]. `event_type` names the
+/// job that produced it. Concurrent calls may share one client:
+/// `tokio_postgres` pipelines their inserts over the single connection, so
+/// no writer task or pool is needed.
+pub async fn log_event(
+    log: &EventLog,
+    client: Option<&Client>,
+    event_type: &str,
+    severity: &str,
+    message: &str,
+    machine: &str,
+) -> Result<(), AppError> {
+    match severity {
+        "Error" => tracing::error!(target: EVENT_LOG_TARGET, event_type, machine, "{}", message),
+        "Warn" => tracing::warn!(target: EVENT_LOG_TARGET, event_type, machine, "{}", message),
+        _ => tracing::info!(target: EVENT_LOG_TARGET, event_type, machine, "{}", message),
+    }
+    let Some(client) = client else {
+        eprintln!("[{}] {} ({}): {}", severity, event_type, machine, message);
+        return Ok(());
+    };
+
+    // An over-long message, such as an HTML error page, must not fail the
+    // insert and with it the run
+    let message = truncate_message(message, log.message_max);
+    if log.writes_instance() {
+        let result = client
+            .execute(
+                "INSERT INTO log (severity, message, machine, event_type, instance) \
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[&severity, &message, &machine, &event_type, &log.instance],
+            )
+            .await;
+        match result {
+            // Every racing insert retries below, so none of their rows is lost
+            Err(e) if e.code() == Some(&SqlState::UNDEFINED_COLUMN) => {
+                if log.mark_instance_missing() {
+                    tracing::warn!("log table has no instance column; writing rows without it");
+                }
+            }
+            result => return result.map(|_| ()).map_err(Into::into),
+        }
+    }
+    client
+        .execute(
+            "INSERT INTO log (severity, message, machine, event_type) VALUES ($1, $2, $3, $4)",
+            &[&severity, &message, &machine, &event_type],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Cuts `message` to `max` characters, the last being an ellipsis.
+fn truncate_message(message: &str, max: usize) -> Cow<'_, str> {
+    match message.char_indices().nth(max) {
+        None => Cow::Borrowed(message),
+        Some(_) => {
+            let kept: String = message.chars().take(max - 1).collect();
+            Cow::Owned(kept + "…")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_log() -> EventLog {
+        EventLog {
+            message_max: 1000,
+            instance: "test".to_string(),
+            instance_column: OnceLock::new(),
+        }
+    }
+
+    #[test]
+    fn concurrent_inserts_mark_the_instance_column_missing_once() {
+        let log = event_log();
+        assert!(log.writes_instance());
+
+        let first = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..32)
+                .map(|_| scope.spawn(|| log.mark_instance_missing()))
+                .collect();
+            racers
+                .into_iter()
+                .map(|racer| racer.join().unwrap())
+                .filter(|&won| won)
+                .count()
+        });
+        assert_eq!(first, 1);
+        assert!(!log.writes_instance());
+    }
+
+    #[test]
+    fn truncates_long_messages() {
+        let cases = [
+            ("short", 10, "short"),
+            ("exactly10!", 10, "exactly10!"),
+            ("eleven long", 10, "eleven lo…"),
+            ("ééééé", 3, "éé…"),
+            ("abc", 1, "…"),
+        ];
+        for (message, max, expected) in cases {
+            assert_eq!(truncate_message(message, max), expected, "{:?}", message);
+        }
+    }
+}
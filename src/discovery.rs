@@ -0,0 +1,83 @@
+use crate::error::AppError;
+use crate::light::{broadcast_address, normalize_mac, WizLight};
+use crate::transport::{bind_udp, UdpBind};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::time::{timeout_at, Duration, Instant};
+use tracing::debug;
+
+const SYSTEM_CONFIG_REQUEST: &str = r#"{"method":"getSystemConfig","params":{}}"#;
+
+#[derive(Deserialize)]
+struct SystemConfigReply {
+    result: SystemConfig,
+}
+
+#[derive(Deserialize)]
+struct SystemConfig {
+    mac: String,
+}
+
+/// A bulb that answered a discovery broadcast.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredLight {
+    pub mac: String,
+    pub addr: SocketAddr,
+}
+
+/// Broadcasts `getSystemConfig` on the subnet and collects every reply that
+/// arrives within `wait`.
+pub async fn discover(
+    bind: UdpBind,
+    network_id: &str,
+    wait: Duration,
+) -> Result<Vec<DiscoveredLight>, AppError> {
+    let socket = bind_udp(bind).await?;
+    socket.set_broadcast(true)?;
+    let broadcast_addr: SocketAddr = broadcast_address(network_id).parse()?;
+    socket
+        .send_to(SYSTEM_CONFIG_REQUEST.as_bytes(), broadcast_addr)
+        .await?;
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<DiscoveredLight> = Vec::new();
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, addr) = received?;
+        match serde_json::from_slice::<SystemConfigReply>(&buf[..len]) {
+            Ok(reply) => {
+                let mac = normalize_mac(&reply.result.mac);
+                if !found.iter().any(|light| light.mac == mac) {
+                    found.push(DiscoveredLight { mac, addr });
+                }
+            }
+            Err(e) => debug!(%addr, error = %e, "ignoring non-WiZ discovery reply"),
+        }
+    }
+
+    Ok(found)
+}
+
+/// Points lights that have a MAC at the address discovery found for it.
+/// Lights discovery did not see keep their stored `host_id`.
+pub fn apply_discovered(lights: &mut [WizLight], discovered: &[DiscoveredLight]) {
+    let by_mac: HashMap<&str, SocketAddr> = discovered
+        .iter()
+        .map(|light| (light.mac.as_str(), light.addr))
+        .collect();
+    for light in lights {
+        let Some(mac) = light.mac.as_deref() else {
+            continue;
+        };
+        match by_mac.get(mac) {
+            Some(addr) => {
+                debug!(name = %light.name, %mac, %addr, "resolved light by MAC");
+                light.host_id = addr.to_string();
+            }
+            None => {
+                debug!(name = %light.name, %mac, fallback = %light.host_id, "MAC not discovered")
+            }
+        }
+    }
+}
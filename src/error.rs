@@ -0,0 +1,126 @@
+use reqwest::Error as ReqwestError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SunriseError {
+    #[error("HTTP request error")]
+    ReqwestError(#[from] ReqwestError),
+    #[error("local sunrise service: {0}")]
+    Socket(#[from] std::io::Error),
+    #[error("sunrise API returned {status}: {snippet}")]
+    Status {
+        status: reqwest::StatusCode,
+        snippet: String,
+    },
+    #[error("unexpected sunrise API response: {snippet}")]
+    InvalidResponse {
+        #[source]
+        source: serde_json::Error,
+        snippet: String,
+    },
+    #[error("sunrise API sent no results: {problem}")]
+    NoResults { problem: String },
+    #[error(
+        "sunrise API returned a 12-hour time {value:?} instead of ISO 8601; the provider is ignoring formatted=0"
+    )]
+    FormattedTimes { value: String },
+    #[error("DateTime parse error in sunrise API response: {snippet}")]
+    ChronoParseError {
+        #[source]
+        source: chrono::ParseError,
+        snippet: String,
+    },
+}
+
+impl SunriseError {
+    /// True for failures that may clear up on their own: server errors, rate
+    /// limiting, timeouts and dropped connections. Other 4xx responses and
+    /// malformed bodies will not improve on retry.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SunriseError::Status { status, .. } => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            SunriseError::ReqwestError(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            SunriseError::Socket(_)
+            | SunriseError::InvalidResponse { .. }
+            | SunriseError::NoResults { .. }
+            | SunriseError::FormattedTimes { .. }
+            | SunriseError::ChronoParseError { .. } => false,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("database error")]
+    Database(#[from] tokio_postgres::Error),
+    #[error("sunrise lookup failed")]
+    Sunrise(#[from] SunriseError),
+    #[error("sunrise API circuit breaker open for another {0:?}")]
+    BreakerOpen(std::time::Duration),
+    #[error("socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid light address: {0}")]
+    AddrParse(#[from] std::net::AddrParseError),
+    #[error("could not resolve light address {host}: {reason}")]
+    Resolve { host: String, reason: String },
+    #[error("invalid light action {0:?}, expected \"off\" or \"on\"")]
+    InvalidAction(String),
+    #[error("invalid light transport {0:?}, expected \"udp\" or \"tcp\"")]
+    InvalidTransport(String),
+    #[error(
+        "invalid firmware profile {0:?}, expected \"standard\", \"state_first\" or \"int_state\""
+    )]
+    InvalidFirmwareProfile(String),
+    #[error("only {sent} of {expected} bytes sent to {addr}")]
+    PartialSend {
+        addr: String,
+        sent: usize,
+        expected: usize,
+    },
+    #[error("cloud fallback failed for light {name:?}: {reason}")]
+    Cloud { name: String, reason: String },
+    #[error("unexpected reply from light: {0}")]
+    InvalidReply(String),
+    #[error("no reply from {0} within {1:?}")]
+    NoReply(String, std::time::Duration),
+    #[error("invalid light command {0}")]
+    InvalidCommand(String),
+    #[error("invalid schedule job {name:?}: {reason}")]
+    InvalidJob { name: String, reason: String },
+    #[error("invalid sequence for light {name:?}: {reason}")]
+    InvalidSequence { name: String, reason: String },
+    #[error("light {name:?} resolves to {addr}, outside NETWORK_ID {network}")]
+    OutsideNetwork {
+        name: String,
+        addr: String,
+        network: String,
+    },
+    #[error("no lights to switch: {0} (ERROR_ON_EMPTY)")]
+    NoLights(String),
+    #[error("{count} lights loaded, more than MAX_LIGHTS ({max}); refusing to run")]
+    TooManyLights { count: usize, max: usize },
+    #[error("could not geocode CITY {city:?}: {reason}")]
+    Geocode { city: String, reason: String },
+    #[error("weather lookup failed: {0}")]
+    Weather(String),
+    #[error("notification failed: {0}")]
+    Notify(String),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("failed to read lights file {path}")]
+    LightsFileRead {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to fetch lights from {url}: {reason}")]
+    LightsFetch { url: String, reason: String },
+    #[error("invalid lights file {path}")]
+    LightsFileParse {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
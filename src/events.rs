@@ -0,0 +1,80 @@
+use crate::light::LightCommand;
+use crate::plan::RunReport;
+use chrono::{DateTime, Local};
+use tokio::sync::broadcast;
+
+/// Something that happened during a run, published on the [`EventBus`]
+/// alongside the usual logging so callers can observe runs without parsing
+/// log messages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunEvent {
+    /// A job's target and lights are settled; nothing is sent yet.
+    Planned {
+        job: String,
+        target: DateTime<Local>,
+        lights: usize,
+    },
+    /// A job is waiting for its target time.
+    Scheduled {
+        job: String,
+        target: DateTime<Local>,
+    },
+    LightToggled {
+        job: String,
+        light: String,
+        command: LightCommand,
+    },
+    LightFailed {
+        job: String,
+        light: String,
+        error: String,
+    },
+    /// An action was left out; `machine` is the light, or "All".
+    Skipped {
+        job: String,
+        machine: String,
+        reason: String,
+    },
+    /// A run ended, whether it switched lights or was skipped.
+    Completed {
+        report: RunReport,
+    },
+    /// A run failed before it could report per-light outcomes.
+    Failed {
+        job: String,
+        error: String,
+    },
+    NotifierSent {
+        job: String,
+        success: bool,
+    },
+}
+
+/// Events buffered per subscriber before the oldest are dropped.
+const EVENT_CAPACITY: usize = 256;
+
+/// Fans [`RunEvent`]s out to every subscriber. Publishing with no
+/// subscribers is a no-op. Clones publish on the same bus.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<RunEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            sender: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: RunEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receives every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<RunEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,41 @@
+use crate::error::AppError;
+use serde::Deserialize;
+
+const GEOCODING_URL: &str = "https://geocoding-api.open-meteo.com/v1/search";
+
+#[derive(Deserialize)]
+struct GeocodingResponse {
+    #[serde(default)]
+    results: Vec<Place>,
+}
+
+#[derive(Deserialize)]
+struct Place {
+    latitude: f64,
+    longitude: f64,
+}
+
+/// Looks up the coordinates of `city` with the Open-Meteo geocoding API,
+/// taking its best match.
+pub async fn geocode_city(http: &reqwest::Client, city: &str) -> Result<(f64, f64), AppError> {
+    let failed = |reason: String| AppError::Geocode {
+        city: city.to_string(),
+        reason,
+    };
+    let response: GeocodingResponse = http
+        .get(GEOCODING_URL)
+        .query(&[("name", city), ("count", "1")])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| failed(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    let place = response
+        .results
+        .first()
+        .ok_or_else(|| failed("no matching place".to_string()))?;
+
+    Ok((place.latitude, place.longitude))
+}
@@ -0,0 +1,31 @@
+// serde_json::json! recurses per key; Config::redacted_json has many
+#![recursion_limit = "256"]
+
+pub mod clock;
+pub mod config;
+pub mod console;
+pub mod controller;
+pub mod csv_log;
+pub mod db;
+pub mod discovery;
+pub mod error;
+pub mod events;
+pub mod geocode;
+pub mod light;
+pub mod notify;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod plan;
+pub mod schedule;
+pub mod season;
+pub mod secret;
+pub mod server;
+pub mod socks;
+pub mod sunrise;
+pub mod transport;
+pub mod weather;
+
+pub use clock::{Clock, FakeClock, SystemClock};
+pub use config::Config;
+pub use error::{AppError, SunriseError};
+pub use plan::{execute, plan_run, RunPlan, RunReport, SkipReason};
@@ -0,0 +1,827 @@
+use crate::config::redact_url;
+use crate::controller::Transport;
+use crate::error::AppError;
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_postgres::Client;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightAction {
+    Off,
+    On,
+}
+
+impl FromStr for LightAction {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(LightAction::Off),
+            "on" => Ok(LightAction::On),
+            _ => Err(AppError::InvalidAction(s.to_string())),
+        }
+    }
+}
+
+/// UDP port WiZ bulbs listen on.
+pub const WIZ_PORT: u16 = 38899;
+
+/// Asks a bulb for its current state.
+pub const GET_PILOT_REQUEST: &str = r#"{"method":"getPilot","params":{}}"#;
+
+#[derive(Deserialize)]
+struct PilotReply {
+    result: Pilot,
+}
+
+#[derive(Deserialize)]
+struct Pilot {
+    state: bool,
+}
+
+/// Whether a `getPilot` reply reports the light as on.
+pub fn pilot_state(reply: &[u8]) -> Result<bool, AppError> {
+    serde_json::from_slice::<PilotReply>(reply)
+        .map(|reply| reply.result.state)
+        .map_err(|e| AppError::InvalidReply(e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct AckReply {
+    result: Ack,
+}
+
+#[derive(Deserialize)]
+struct Ack {
+    success: bool,
+}
+
+/// Checks a `setPilot` reply reports success.
+pub fn check_ack(reply: &[u8]) -> Result<(), AppError> {
+    let reply: AckReply =
+        serde_json::from_slice(reply).map_err(|e| AppError::InvalidReply(e.to_string()))?;
+    if !reply.result.success {
+        return Err(AppError::InvalidReply("bulb reported failure".to_string()));
+    }
+    Ok(())
+}
+
+/// The `setPilot` parameters sent to a bulb.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LightCommand {
+    pub state: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimming: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp: Option<u16>,
+    /// Dynamic scene speed. Only sent with on-commands.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<u8>,
+    /// Fade-in duration in seconds. Only sent with on-commands.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp: Option<u16>,
+    /// Leave `state` out of the payload. Set when the state was only implied
+    /// by a dimming level or temperature, since some firmware treats a
+    /// combined payload differently from brightness alone.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub minimal: bool,
+}
+
+impl LightCommand {
+    /// Turns a light off; its payload is
+    /// `{"method":"setPilot","params":{"state":false}}`.
+    pub fn off() -> Self {
+        LightCommand {
+            state: false,
+            dimming: None,
+            temp: None,
+            speed: None,
+            ramp: None,
+            minimal: false,
+        }
+    }
+
+    /// The companion of [`LightCommand::off`] used for every on-action, so
+    /// both go through [`LightCommand::payload`]. Its payload is
+    /// `{"method":"setPilot","params":{"state":true}}`.
+    pub fn on() -> Self {
+        LightCommand {
+            state: true,
+            ..LightCommand::off()
+        }
+    }
+
+    /// The `setPilot` request in the [`FirmwareProfile::Standard`] layout.
+    pub fn payload(&self) -> String {
+        self.payload_for(FirmwareProfile::Standard)
+    }
+
+    /// The `setPilot` request laid out as `profile` expects.
+    pub fn payload_for(&self, profile: FirmwareProfile) -> String {
+        let mut params = self.clone();
+        if !params.state {
+            params.speed = None;
+            params.ramp = None;
+        }
+        let mut params = serde_json::json!(params);
+        if let Some(params) = params.as_object_mut() {
+            params.remove("minimal");
+            if self.minimal {
+                params.remove("state");
+            }
+        }
+        let serde_json::Value::Object(mut params) = params else {
+            unreachable!("commands serialize to objects");
+        };
+
+        match profile {
+            FirmwareProfile::Standard => {
+                serde_json::json!({ "method": "setPilot", "params": params }).to_string()
+            }
+            FirmwareProfile::StateFirst | FirmwareProfile::IntState => {
+                if profile == FirmwareProfile::IntState {
+                    if let Some(state) = params.get_mut("state") {
+                        *state = serde_json::json!(u8::from(self.state));
+                    }
+                }
+                // serde_json maps sort their keys, so the order is written
+                // out by hand
+                let fields: Vec<String> = ["state", "dimming", "temp", "speed", "ramp"]
+                    .iter()
+                    .filter_map(|key| params.remove(*key).map(|value| (key, value)))
+                    .map(|(key, value)| format!("\"{}\":{}", key, value))
+                    .collect();
+                format!(
+                    r#"{{"method":"setPilot","params":{{{}}}}}"#,
+                    fields.join(",")
+                )
+            }
+        }
+    }
+}
+
+/// How a bulb's firmware wants `setPilot` laid out, per light via the
+/// `firmware_profile` column. Older firmware is picky about the structure
+/// that current bulbs accept in any form.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FirmwareProfile {
+    /// `standard`: the payload as always sent, e.g.
+    /// `{"method":"setPilot","params":{"dimming":30,"state":true}}`, with
+    /// keys in alphabetical order.
+    #[default]
+    Standard,
+    /// `state_first`: `state` leads, followed by `dimming`, `temp`, `speed`
+    /// and `ramp`, e.g.
+    /// `{"method":"setPilot","params":{"state":true,"dimming":30}}`.
+    StateFirst,
+    /// `int_state`: as `state_first`, but with `state` as `1` or `0`, e.g.
+    /// `{"method":"setPilot","params":{"state":1,"dimming":30}}`.
+    IntState,
+}
+
+impl FromStr for FirmwareProfile {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "standard" => Ok(FirmwareProfile::Standard),
+            "state_first" => Ok(FirmwareProfile::StateFirst),
+            "int_state" => Ok(FirmwareProfile::IntState),
+            _ => Err(AppError::InvalidFirmwareProfile(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for LightCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.state { "on" } else { "off" })?;
+        let mut details = Vec::new();
+        if let Some(dimming) = self.dimming {
+            details.push(format!("dimming {}%", dimming));
+        }
+        if let Some(temp) = self.temp {
+            details.push(format!("{}K", temp));
+        }
+        if self.state {
+            if let Some(speed) = self.speed {
+                details.push(format!("speed {}", speed));
+            }
+            if let Some(ramp) = self.ramp {
+                details.push(format!("{}s fade", ramp));
+            }
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses commands such as `off`, `on` or `dim:10,temp:2700,ramp:30`.
+/// Setting a dimming level or temperature implies the light is on; without
+/// an explicit `on` the payload then carries only those fields. `speed` and
+/// `ramp` are ignored for off-commands.
+impl FromStr for LightCommand {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| AppError::InvalidCommand(format!("{:?}: {}", s, reason));
+        let mut command = LightCommand::off();
+        let mut explicit_state = false;
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once(':') {
+                None if part.eq_ignore_ascii_case("off") => {
+                    command.state = false;
+                    explicit_state = true;
+                }
+                None if part.eq_ignore_ascii_case("on") => {
+                    command.state = true;
+                    explicit_state = true;
+                }
+                Some(("dim", value)) => {
+                    let dimming: u8 = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid("dim must be a number"))?;
+                    if !(10..=100).contains(&dimming) {
+                        return Err(invalid("dim must be between 10 and 100"));
+                    }
+                    command.state = true;
+                    command.dimming = Some(dimming);
+                }
+                Some(("temp", value)) => {
+                    let temp: u16 = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid("temp must be a number"))?;
+                    if !(2200..=6500).contains(&temp) {
+                        return Err(invalid("temp must be between 2200 and 6500"));
+                    }
+                    command.state = true;
+                    command.temp = Some(temp);
+                }
+                Some(("speed", value)) => {
+                    let speed: u8 = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid("speed must be a number"))?;
+                    if !(10..=200).contains(&speed) {
+                        return Err(invalid("speed must be between 10 and 200"));
+                    }
+                    command.speed = Some(speed);
+                }
+                Some(("ramp", value)) => {
+                    let ramp: u16 = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid("ramp must be a number of seconds"))?;
+                    command.ramp = Some(ramp);
+                }
+                _ => return Err(invalid(&format!("unknown setting {:?}", part))),
+            }
+        }
+        command.minimal = !explicit_state && (command.dimming.is_some() || command.temp.is_some());
+        Ok(command)
+    }
+}
+
+/// One step of a per-light sequence: a command, then a pause before the
+/// next step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequenceStep {
+    pub command: LightCommand,
+    pub delay: Duration,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SequenceStepRecord {
+    command: String,
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+/// Validates a `sequence` value: a non-empty array of
+/// `{"command": "...", "delay_ms": ...}` objects whose commands use the
+/// same syntax as `MORNING_COMMAND`.
+pub fn parse_sequence(name: &str, value: serde_json::Value) -> Result<Vec<SequenceStep>, AppError> {
+    let invalid = |reason: String| AppError::InvalidSequence {
+        name: name.to_string(),
+        reason,
+    };
+    let records: Vec<SequenceStepRecord> =
+        serde_json::from_value(value).map_err(|e| invalid(e.to_string()))?;
+    if records.is_empty() {
+        return Err(invalid("sequence has no steps".to_string()));
+    }
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            let command = record
+                .command
+                .parse()
+                .map_err(|e| invalid(format!("step {}: {}", index + 1, e)))?;
+            Ok(SequenceStep {
+                command,
+                delay: Duration::from_millis(record.delay_ms),
+            })
+        })
+        .collect()
+}
+
+/// Dimming level a ramp starts from; the lowest a bulb accepts.
+const RAMP_START_DIMMING: u8 = 10;
+
+/// Time between the steps of a ramp.
+const RAMP_STEP: Duration = Duration::from_secs(60);
+
+/// Steps that raise the brightness evenly from the minimum to `command`'s
+/// dimming (full when unset) over `duration`, ending with `command` itself.
+pub fn ramp_sequence(command: &LightCommand, duration: Duration) -> Vec<SequenceStep> {
+    let target = command.dimming.unwrap_or(100);
+    let intervals = (duration.as_secs() / RAMP_STEP.as_secs()).max(1) as u32;
+    let delay = duration / intervals;
+    (0..=intervals)
+        .map(|step| {
+            let span = u32::from(target.saturating_sub(RAMP_START_DIMMING));
+            let dimming = RAMP_START_DIMMING as u32 + span * step / intervals;
+            SequenceStep {
+                // Explicit state, since a ramp usually starts from off
+                command: LightCommand {
+                    state: true,
+                    dimming: Some(dimming as u8),
+                    minimal: false,
+                    ..command.clone()
+                },
+                delay,
+            }
+        })
+        .collect()
+}
+
+/// A `machine` row as stored in the database or a lights file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LightRecord {
+    pub host_id: String,
+    pub name: String,
+    #[serde(default = "default_action")]
+    pub action: String,
+    #[serde(default = "default_transport")]
+    pub transport: String,
+    /// Overrides `NETWORK_ID` for this light.
+    #[serde(default)]
+    pub network_id: Option<String>,
+    /// Identifies the bulb across DHCP changes; see [`crate::discovery`].
+    #[serde(default)]
+    pub mac: Option<String>,
+    /// Steps run instead of the single command; see [`parse_sequence`].
+    #[serde(default)]
+    pub sequence: Option<serde_json::Value>,
+    /// Position in the run with `ORDER_BY=order`.
+    #[serde(default)]
+    pub sort_order: Option<i32>,
+    /// Whether the light must acknowledge commands. Defaults to true for
+    /// TCP and false for UDP.
+    #[serde(default)]
+    pub require_ack: Option<bool>,
+    #[serde(default)]
+    pub room: Option<String>,
+    /// See [`FirmwareProfile`]; `standard` when unset.
+    #[serde(default)]
+    pub firmware_profile: Option<String>,
+}
+
+fn default_action() -> String {
+    "off".to_string()
+}
+
+fn default_transport() -> String {
+    "udp".to_string()
+}
+
+#[derive(Clone, Debug)]
+pub struct WizLight {
+    pub host_id: String,
+    pub name: String,
+    pub action: LightAction,
+    pub transport: Transport,
+    /// When set, the current address is looked up by discovery and
+    /// `host_id` is only the fallback.
+    pub mac: Option<String>,
+    /// Run instead of the job's default command when set.
+    pub sequence: Option<Vec<SequenceStep>>,
+    pub sort_order: Option<i32>,
+    /// Wait for and check the bulb's reply; otherwise a send that leaves
+    /// this host counts as success.
+    pub require_ack: bool,
+    /// Groups lights for `--room` and the run summary.
+    pub room: Option<String>,
+    pub firmware_profile: FirmwareProfile,
+}
+
+impl WizLight {
+    /// An ad-hoc light addressed directly, named after its address. A bare
+    /// last octet is resolved within `network_id`.
+    pub fn from_addr(addr: &str, network_id: &str) -> Self {
+        WizLight {
+            host_id: light_address(addr, network_id),
+            name: addr.trim().to_string(),
+            action: LightAction::Off,
+            transport: Transport::Udp,
+            mac: None,
+            sequence: None,
+            sort_order: None,
+            require_ack: false,
+            room: None,
+            firmware_profile: FirmwareProfile::default(),
+        }
+    }
+}
+
+pub async fn fetch_wiz_lights(
+    client: &Client,
+    network_id: &str,
+) -> Result<Vec<WizLight>, AppError> {
+    let rows = client
+        .query(
+            "SELECT host_id, name, COALESCE(action, 'off') AS action, \
+             COALESCE(transport, 'udp') AS transport, network_id, mac, \
+             sequence::text AS sequence, sort_order, require_ack, room, \
+             firmware_profile FROM machine",
+            &[],
+        )
+        .await?;
+
+    let records = rows
+        .iter()
+        .map(|row| {
+            let name: String = row.get("name");
+            let sequence = row
+                .get::<_, Option<String>>("sequence")
+                .map(|text| serde_json::from_str(&text))
+                .transpose()
+                .map_err(|e| AppError::InvalidSequence {
+                    name: name.clone(),
+                    reason: e.to_string(),
+                })?;
+            Ok(LightRecord {
+                host_id: row.get("host_id"),
+                name,
+                action: row.get("action"),
+                transport: row.get("transport"),
+                network_id: row.get("network_id"),
+                mac: row.get("mac"),
+                sequence,
+                sort_order: row.get("sort_order"),
+                require_ack: row.get("require_ack"),
+                room: row.get("room"),
+                firmware_profile: row.get("firmware_profile"),
+            })
+        })
+        .collect::<Result<_, AppError>>()?;
+
+    build_wiz_lights(records, network_id)
+}
+
+/// Loads lights from a JSON file holding an array of `machine` rows, used
+/// when the database is unavailable.
+pub fn load_wiz_lights_file(path: &str, network_id: &str) -> Result<Vec<WizLight>, AppError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| AppError::LightsFileRead {
+        path: path.to_string(),
+        source,
+    })?;
+    let records: Vec<LightRecord> =
+        serde_json::from_str(&contents).map_err(|source| AppError::LightsFileParse {
+            path: path.to_string(),
+            source,
+        })?;
+
+    build_wiz_lights(records, network_id)
+}
+
+/// The last `LIGHTS_URL` response that parsed, reused when a later fetch
+/// fails.
+#[derive(Debug, Default)]
+pub struct LightsCache(std::sync::Mutex<Option<Vec<LightRecord>>>);
+
+/// Loads lights from `url`, which returns the same JSON array as
+/// `LIGHTS_FILE`. When the request or its body fails, the copy from the
+/// last successful fetch is used if there is one.
+pub async fn fetch_wiz_lights_http(
+    http: &reqwest::Client,
+    url: &str,
+    network_id: &str,
+    cache: &LightsCache,
+) -> Result<Vec<WizLight>, AppError> {
+    let fetched = async {
+        http.get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<LightRecord>>()
+            .await
+    }
+    .await;
+    let records = match fetched {
+        Ok(records) => {
+            *cache.0.lock().unwrap() = Some(records.clone());
+            records
+        }
+        Err(e) => {
+            let cached = cache.0.lock().unwrap().clone();
+            let Some(records) = cached else {
+                return Err(AppError::LightsFetch {
+                    url: redact_url(url),
+                    reason: e.without_url().to_string(),
+                });
+            };
+            let e = e.without_url();
+            tracing::warn!(url = %redact_url(url), error = %e, "LIGHTS_URL fetch failed; using the cached lights");
+            records
+        }
+    };
+
+    build_wiz_lights(records, network_id)
+}
+
+fn build_wiz_lights(
+    records: Vec<LightRecord>,
+    network_id: &str,
+) -> Result<Vec<WizLight>, AppError> {
+    let mut wiz_lights = Vec::new();
+    for record in records {
+        let network_id = record.network_id.as_deref().unwrap_or(network_id);
+        let sequence = record
+            .sequence
+            .map(|value| parse_sequence(&record.name, value))
+            .transpose()?;
+        let transport: Transport = record.transport.parse()?;
+        let host_id = light_address(&record.host_id, network_id);
+        check_in_network(&record.name, &host_id, network_id)?;
+        wiz_lights.push(WizLight {
+            host_id,
+            name: record.name,
+            action: record.action.parse()?,
+            transport,
+            mac: record.mac.as_deref().map(normalize_mac),
+            sequence,
+            sort_order: record.sort_order,
+            require_ack: record.require_ack.unwrap_or(transport == Transport::Tcp),
+            room: record.room,
+            firmware_profile: record
+                .firmware_profile
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+        });
+    }
+
+    warn_duplicate_names(&wiz_lights);
+    Ok(wiz_lights)
+}
+
+/// Log events are attributed by light name, so lights sharing a name make
+/// them ambiguous. Each shared name is reported once with its addresses.
+fn warn_duplicate_names(lights: &[WizLight]) {
+    for (index, light) in lights.iter().enumerate() {
+        if lights[..index].iter().any(|other| other.name == light.name) {
+            continue;
+        }
+        let hosts: Vec<&str> = lights
+            .iter()
+            .filter(|other| other.name == light.name)
+            .map(|other| other.host_id.as_str())
+            .collect();
+        if hosts.len() > 1 {
+            tracing::warn!(
+                name = %light.name,
+                hosts = %hosts.join(", "),
+                "several lights share a name; their log events cannot be told apart"
+            );
+        }
+    }
+}
+
+/// Builds `ip:port` for a light. A `host_id` containing dots is already a
+/// full address and is used as-is; otherwise it is the last octet within
+/// `network_id`, or with a CIDR `network_id` the host number within it.
+fn light_address(host_id: &str, network_id: &str) -> String {
+    let host_id = host_id.trim();
+    if let (false, Ok(network), Ok(host)) = (
+        host_id.contains('.'),
+        network_id.parse::<Ipv4Net>(),
+        host_id.parse::<u32>(),
+    ) {
+        let addr = Ipv4Addr::from(u32::from(network.network()).wrapping_add(host));
+        format!("{}:{}", addr, WIZ_PORT)
+    } else if !host_id.contains('.') {
+        format!("{}.{}:{}", network_id, host_id, WIZ_PORT)
+    } else if host_id.contains(':') {
+        host_id.to_string()
+    } else {
+        format!("{}:{}", host_id, WIZ_PORT)
+    }
+}
+
+/// Lowercase hex digits without separators, as reported by `getSystemConfig`.
+pub fn normalize_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(char::is_ascii_hexdigit)
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// The order lights are switched in during a run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightOrder {
+    /// As returned by the database or listed in the file.
+    #[default]
+    Source,
+    /// Alphabetically by name, ignoring case.
+    Name,
+    /// By the `sort_order` column, lights without one last.
+    Order,
+}
+
+impl FromStr for LightOrder {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "source" | "none" => Ok(LightOrder::Source),
+            "name" => Ok(LightOrder::Name),
+            "order" => Ok(LightOrder::Order),
+            _ => Err(AppError::InvalidConfig(format!(
+                "ORDER_BY must be source, name or order, got {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Sorts lights in place; ties keep their source order.
+pub fn order_lights(lights: &mut [WizLight], order: LightOrder) {
+    match order {
+        LightOrder::Source => {}
+        LightOrder::Name => lights.sort_by_key(|light| light.name.to_ascii_lowercase()),
+        LightOrder::Order => {
+            lights.sort_by_key(|light| (light.sort_order.is_none(), light.sort_order))
+        }
+    }
+}
+
+/// Splits lights into those to switch and those skipped, with the reason for
+/// each skip. Names match case-insensitively; an include list takes
+/// precedence over the exclude list.
+pub fn filter_lights(
+    lights: Vec<WizLight>,
+    include: Option<&[String]>,
+    exclude: &[String],
+) -> (Vec<WizLight>, Vec<(WizLight, &'static str)>) {
+    let listed = |names: &[String], light: &WizLight| {
+        names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&light.name))
+    };
+    let mut kept = Vec::new();
+    let mut skipped = Vec::new();
+    for light in lights {
+        match include {
+            Some(include) if !listed(include, &light) => {
+                skipped.push((light, "not in INCLUDE_LIGHTS"))
+            }
+            None if listed(exclude, &light) => skipped.push((light, "listed in EXCLUDE_LIGHTS")),
+            _ => kept.push(light),
+        }
+    }
+    (kept, skipped)
+}
+
+/// With a CIDR `network_id` (e.g. `192.168.1.0/24`), rejects a light whose
+/// address falls outside it, which usually means a typo in `host_id`.
+/// Addresses given by hostname are not checked.
+fn check_in_network(name: &str, host_id: &str, network_id: &str) -> Result<(), AppError> {
+    let (Ok(network), Ok(addr)) = (network_id.parse::<Ipv4Net>(), host_id.parse::<SocketAddr>())
+    else {
+        return Ok(());
+    };
+    match addr.ip() {
+        std::net::IpAddr::V4(ip) if network.contains(&ip) => Ok(()),
+        ip => Err(AppError::OutsideNetwork {
+            name: name.to_string(),
+            addr: ip.to_string(),
+            network: network.to_string(),
+        }),
+    }
+}
+
+/// The subnet broadcast address for `network_id`: a bare prefix's `.255`,
+/// or a CIDR network's broadcast address.
+pub fn broadcast_address(network_id: &str) -> String {
+    match network_id.parse::<Ipv4Net>() {
+        Ok(network) => format!("{}:{}", network.broadcast(), WIZ_PORT),
+        Err(_) => format!("{}.255:{}", network_id, WIZ_PORT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_into_payloads() {
+        let cases = [
+            ("off", r#"{"method":"setPilot","params":{"state":false}}"#),
+            ("on", r#"{"method":"setPilot","params":{"state":true}}"#),
+            ("dim:30", r#"{"method":"setPilot","params":{"dimming":30}}"#),
+            (
+                "on, dim:30",
+                r#"{"method":"setPilot","params":{"dimming":30,"state":true}}"#,
+            ),
+            (
+                "on,temp:2700,speed:50,ramp:30",
+                r#"{"method":"setPilot","params":{"ramp":30,"speed":50,"state":true,"temp":2700}}"#,
+            ),
+            (
+                "off,speed:50,ramp:30",
+                r#"{"method":"setPilot","params":{"state":false}}"#,
+            ),
+        ];
+        for (input, payload) in cases {
+            let command: LightCommand = input.parse().unwrap();
+            assert_eq!(command.payload(), payload, "{}", input);
+        }
+    }
+
+    #[test]
+    fn payloads_parse_back_to_the_command() {
+        for input in ["off", "on", "on,dim:30", "on,temp:2700,speed:50,ramp:30"] {
+            let command: LightCommand = input.parse().unwrap();
+            let request: serde_json::Value = serde_json::from_str(&command.payload()).unwrap();
+            let params: LightCommand = serde_json::from_value(request["params"].clone()).unwrap();
+            assert_eq!(params, command, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for input in [
+            "dim:5",
+            "dim:x",
+            "temp:9000",
+            "speed:5",
+            "ramp:-1",
+            "bright:50",
+            "toggle",
+        ] {
+            assert!(input.parse::<LightCommand>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn default_profile_matches_the_old_payloads() {
+        let profile = FirmwareProfile::default();
+        assert_eq!(
+            LightCommand::off().payload_for(profile),
+            r#"{"method":"setPilot","params":{"state":false}}"#
+        );
+        assert_eq!(
+            LightCommand::on().payload_for(profile),
+            r#"{"method":"setPilot","params":{"state":true}}"#
+        );
+        for input in ["dim:30", "on,dim:30", "on,temp:2700,speed:50,ramp:30"] {
+            let command: LightCommand = input.parse().unwrap();
+            assert_eq!(command.payload_for(profile), command.payload(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn lays_out_each_firmware_profile() {
+        let command: LightCommand = "on,dim:30".parse().unwrap();
+        let cases = [
+            (
+                "standard",
+                r#"{"method":"setPilot","params":{"dimming":30,"state":true}}"#,
+            ),
+            (
+                "STATE_FIRST",
+                r#"{"method":"setPilot","params":{"state":true,"dimming":30}}"#,
+            ),
+            (
+                " int_state ",
+                r#"{"method":"setPilot","params":{"state":1,"dimming":30}}"#,
+            ),
+        ];
+        for (name, payload) in cases {
+            let profile: FirmwareProfile = name.parse().unwrap();
+            assert_eq!(command.payload_for(profile), payload, "{}", name);
+        }
+        assert!("legacy".parse::<FirmwareProfile>().is_err());
+    }
+}
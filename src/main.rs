@@ -1,186 +1,1286 @@
-use chrono::{DateTime, Local, Utc};
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate};
+use clap::Parser;
 use dotenv::dotenv;
-use reqwest::Error as ReqwestError;
-use serde::Deserialize;
-use std::env;
-use std::net::SocketAddr;
-use thiserror::Error;
-use tokio::net::UdpSocket;
-use tokio::time::{sleep, Duration};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use morning_lights_off::console::{ColorMode, Console};
+use morning_lights_off::csv_log::CsvLayer;
+use morning_lights_off::db::{check_schema, is_alive, EVENT_LOG_TARGET};
+use morning_lights_off::db::{
+    load_last_run_failures, log_event, log_light_event, log_run_summary, prune_logs,
+    save_discovered_light, store_observation,
+};
+use morning_lights_off::discovery::discover;
+use morning_lights_off::events::RunEvent;
+use morning_lights_off::light::{fetch_wiz_lights, pilot_state, LightCommand, GET_PILOT_REQUEST};
+use morning_lights_off::notify::notify_events;
+use morning_lights_off::plan::{
+    forecast_job, keepalive, load_lights, plan_job, switch, switch_planned, wait_for_lights,
+    wait_for_target, Outcome, PlannedLight, Targets,
+};
+use morning_lights_off::schedule::{load_schedule, Job, MANUAL_JOB, MORNING_JOB, STARTUP_JOB};
+use morning_lights_off::server::serve_webhook;
+use morning_lights_off::sunrise::latency_summary;
+use morning_lights_off::transport::{
+    query_udp, resolve, send_udp_packet, udp_self_test, wait_for_bind,
+};
+use morning_lights_off::{plan_run, Clock, Config, FakeClock, RunPlan, RunReport, SystemClock};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
 use tokio_postgres::Client;
 use tokio_postgres::NoTls;
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
 
-#[derive(Deserialize)]
-struct SunriseSunsetResponse {
-    results: Results,
-}
-
-#[derive(Deserialize)]
-struct Results {
-    sunrise: String,
-    // sunset: String,
-    // solar_noon: String,
-    // day_length: String,
-    // civil_twilight_begin: String,
-    // civil_twilight_end: String,
-    // nautical_twilight_begin: String,
-    // nautical_twilight_end: String,
-    // astronomical_twilight_begin: String,
-    // astronomical_twilight_end: String,
-}
+/// Job name under which `--replay-failures` logs and records its run.
+const REPLAY_JOB: &str = "Replay";
 
-struct WizLight {
-    host_id: String,
-    name: String,
-}
+/// Job name of the synthetic run sent by `--test-notifiers`.
+const TEST_JOB: &str = "Test";
 
-#[derive(Error, Debug)]
-enum SunriseError {
-    #[error("HTTP request error")]
-    ReqwestError(#[from] ReqwestError),
-    #[error("DateTime parse error")]
-    ChronoParseError(#[from] chrono::ParseError),
+#[derive(Parser)]
+#[command(version, about = "Turns WiZ lights off shortly before sunrise")]
+struct Cli {
+    /// Send the morning command once to the subnet broadcast address instead
+    /// of addressing each light. Best-effort: bulbs do not acknowledge it.
+    #[arg(long)]
+    broadcast: bool,
+    /// Keep running and switch the lights every morning instead of once.
+    /// Same as MODE=daemon.
+    #[arg(long)]
+    daemon: bool,
+    /// Only print errors to the console.
+    #[arg(long, short)]
+    quiet: bool,
+    /// List the bulbs that answer a discovery broadcast, then exit.
+    #[arg(long)]
+    discover: bool,
+    /// With --discover, add newly found bulbs to the machine table.
+    #[arg(long, requires = "discover")]
+    save: bool,
+    /// Print when the lights will next be switched, then exit without
+    /// sending anything.
+    #[arg(long, conflicts_with_all = ["daemon", "discover"])]
+    next: bool,
+    /// Resend the commands that failed in the most recent run, then exit.
+    #[arg(long, conflicts_with_all = ["daemon", "discover", "next"])]
+    replay_failures: bool,
+    /// Plan the morning run and switch the lights right away, logged as a
+    /// manual run.
+    #[arg(long, conflicts_with_all = ["daemon", "discover", "next", "replay_failures"])]
+    run_now: bool,
+    /// Switch only this light (IP address, optionally with port), without
+    /// reading the machine table. Repeatable.
+    #[arg(long = "light", value_name = "ADDR")]
+    lights: Vec<String>,
+    /// Command to send instead of MORNING_COMMAND, e.g. "on" or "dim:30".
+    #[arg(long)]
+    command: Option<LightCommand>,
+    /// Print the planned action times for this many days, starting today,
+    /// then exit without sending anything.
+    #[arg(long, value_name = "DAYS", conflicts_with_all = ["daemon", "date", "discover", "next"])]
+    forecast: Option<u32>,
+    /// Delete old rows from the log table, then exit.
+    #[arg(long, conflicts_with_all = ["daemon", "discover", "next", "forecast"])]
+    prune_logs: bool,
+    /// With --prune-logs, the age of the rows to delete, e.g. "30days".
+    /// Defaults to LOG_RETENTION.
+    #[arg(long, requires = "prune_logs")]
+    older_than: Option<humantime::Duration>,
+    /// Testing only: pretend the current time is this RFC 3339 timestamp for
+    /// a one-shot run, e.g. to reproduce a DST bug.
+    #[arg(long, hide = true, conflicts_with = "daemon")]
+    now: Option<DateTime<FixedOffset>>,
+    /// Turn the lights on now, ignoring the schedule, then exit.
+    #[arg(long, conflicts_with_all = ["daemon", "turn_off", "run_now"])]
+    turn_on: bool,
+    /// Turn the lights off now, ignoring the schedule, then exit.
+    #[arg(long, conflicts_with_all = ["daemon", "run_now"])]
+    turn_off: bool,
+    /// With --turn-on or --turn-off, only switch the lights in this room.
+    /// Repeatable.
+    #[arg(long = "room", value_name = "ROOM")]
+    rooms: Vec<String>,
+    /// Blink the light with this name a few times so it can be found, then
+    /// exit.
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["daemon", "discover", "next", "run_now"])]
+    identify: Option<String>,
+    /// With --identify, how many times to blink.
+    #[arg(long, default_value_t = 3, requires = "identify")]
+    blinks: u32,
+    /// Send a test run-complete and failure notification through every
+    /// configured notifier and report which succeeded, then exit.
+    #[arg(long, conflicts_with_all = ["daemon", "discover", "next", "run_now"])]
+    test_notifiers: bool,
+    /// Check that the machine and log tables have the columns and types
+    /// this version expects, report any mismatch, then exit. Read-only.
+    #[arg(long, conflicts_with_all = ["daemon", "discover", "next", "run_now"])]
+    check_schema: bool,
+    /// Print the resolved configuration as JSON, secrets masked, then exit.
+    #[arg(long)]
+    dump_config: bool,
+    /// Print each setting with where it came from (environment, .env
+    /// file, command line or default), secrets masked, then exit.
+    #[arg(long, conflicts_with = "dump_config")]
+    explain_config: bool,
+    /// Print each run's per-light results as JSON.
+    #[arg(long)]
+    json: bool,
+    /// Plan for this date (YYYY-MM-DD) instead of today.
+    #[arg(long, conflicts_with = "daemon")]
+    date: Option<NaiveDate>,
+    /// Colorize console output: auto, always or never.
+    #[arg(long, default_value = "auto")]
+    color: ColorMode,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    // Remembered so --explain-config can tell .env values apart
+    let process_vars: HashSet<String> = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .collect();
     dotenv().ok();
-    let db_host = env::var("DB_HOST").expect("DB_HOST not set");
-    let db_user = env::var("DB_USER").expect("DB_USER not set");
-    let db_password = env::var("DB_PASSWORD").expect("DB_PASSWORD not set");
-    let db_name: String = env::var("DB_NAME").expect("DB_NAME not set");
-
-    let conn_str = format!(
-        "host={} user={} password={} dbname={}",
-        db_host, db_user, db_password, db_name
-    );
-    let (client, connection) = tokio_postgres::connect(&conn_str, NoTls).await?;
+    let _tracing_guard = init_tracing().context("setting up tracing")?;
+    let mut config = Config::from_env().await.context("loading configuration")?;
+    config.broadcast = cli.broadcast;
+    // MODE=daemon can be overridden per invocation by one-shot flags
+    config.daemon = (config.daemon || cli.daemon) && cli.date.is_none() && !cli.run_now;
+    config.run_now = cli.run_now;
+    config.lights = cli.lights.clone();
+    if let Some(command) = cli.command.clone() {
+        config.morning_command = command;
+    }
+    config.date = cli.date;
+    config.json = cli.json;
+    config.console = Console::new(cli.quiet, cli.color, cli.json);
+    if cli.dump_config {
+        println!("{}", serde_json::to_string_pretty(&config.redacted_json())?);
+        return Ok(());
+    }
+    if cli.explain_config {
+        let overridden = [
+            ("broadcast", cli.broadcast),
+            ("daemon", cli.daemon || cli.date.is_some() || cli.run_now),
+            ("run_now", cli.run_now),
+            ("lights", !cli.lights.is_empty()),
+            ("morning_command", cli.command.is_some()),
+            ("date", cli.date.is_some()),
+            ("json", cli.json),
+        ];
+        let overridden: Vec<&str> = overridden
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| *name)
+            .collect();
+        for setting in config.explain(&process_vars, &overridden) {
+            println!(
+                "{:<24} {:<32} {}",
+                setting.name,
+                setting.value.to_string(),
+                setting.source
+            );
+        }
+        return Ok(());
+    }
+
+    let (client, connection) = match connect(&config).await {
+        Ok((client, connection)) => (Some(client), Some(connection)),
+        // Lights given on the command line don't need the database
+        Err(e) if !config.require_db || !config.lights.is_empty() => {
+            // Same precedence as the light source itself, minus the database
+            let source = if !config.lights.is_empty() {
+                "--light"
+            } else if config.lights_url.is_some() {
+                "LIGHTS_URL"
+            } else {
+                "LIGHTS_FILE"
+            };
+            config.console.warn(&format!(
+                "database unavailable ({}); continuing with {} and stderr logging",
+                e, source
+            ));
+            (None, None)
+        }
+        Err(e) => return Err(e).context("connecting to database"),
+    };
 
-    // Spawn the connection to run in the background
-    tokio::spawn(async move {
+    // The notifier follows runs on the event bus
+    let (stop_notifier, notifier_stopped) = oneshot::channel();
+    let notifier = tokio::spawn(notify_events(
+        Arc::clone(&config.notifier),
+        config.events.clone(),
+        config.events.subscribe(),
+        config.console,
+        notifier_stopped,
+    ));
+    let result = run(&cli, &mut config, client.as_ref()).await;
+    if let Err(e) = &result {
+        notify_failure(&config, MORNING_JOB, &format!("{:#}", e));
+    }
+    // Still sends what was published before exiting
+    let _ = stop_notifier.send(());
+    let _ = notifier.await;
+
+    // Dropping the client ends the connection task, so a one-shot run exits
+    // as soon as its work is done
+    drop(client);
+    if let Some(connection) = connection {
+        let _ = connection.await;
+    }
+    result
+}
+
+/// Connects to the database, running the connection in the background.
+async fn connect(config: &Config) -> Result<(Client, JoinHandle<()>), tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::connect(&config.connection_string(), NoTls).await?;
+    let connection = tokio::spawn(async move {
         if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+            tracing::error!("connection error: {}", e);
         }
     });
+    Ok((client, connection))
+}
 
-    let wiz_lights = fetch_wiz_lights(&client).await?;
-    let sunrise_utc = fetch_sunrise_time().await?;
-    let sunrise_local = sunrise_utc.with_timezone(&Local);
+/// Flushes `LOG_FILE` and any queued OTLP spans on drop.
+struct TracingGuard {
+    _log_file: Option<WorkerGuard>,
+    #[cfg(feature = "otel")]
+    _otel: Option<morning_lights_off::otel::OtelGuard>,
+}
 
-    // Calculate the target time (30 minutes before sunrise)
-    let target_time = sunrise_local - chrono::Duration::minutes(30);
+/// Sends diagnostics to stderr and, with `LOG_FILE`, copies the logged
+/// events and any warnings to a file rotated per `LOG_ROTATION` (daily by
+/// default). `CSV_LOG` appends the logged events to a CSV file. Built with
+/// the `otel` feature, `OTEL_EXPORTER_OTLP_ENDPOINT` also exports spans
+/// over OTLP. The returned guard flushes the outputs on drop.
+fn init_tracing() -> anyhow::Result<TracingGuard> {
+    // Diagnostics are opt-in via RUST_LOG, e.g. RUST_LOG=morning_lights_off=debug.
+    // Logged events already reach the console, so stderr skips them.
+    let stderr_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("warn"))
+        .add_directive(format!("{}=off", EVENT_LOG_TARGET).parse()?);
+    let stderr_layer = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(stderr_filter);
+    let csv_layer = match std::env::var("CSV_LOG") {
+        Ok(path) => Some(
+            CsvLayer::open(Path::new(&path))
+                .with_context(|| format!("opening CSV_LOG {:?}", path))?,
+        ),
+        Err(_) => None,
+    };
+    #[cfg(feature = "otel")]
+    let (otel_layer, otel_guard) = match morning_lights_off::otel::tracer_from_env()? {
+        Some((tracer, guard)) => (
+            Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+            Some(guard),
+        ),
+        None => (None, None),
+    };
+    #[cfg(not(feature = "otel"))]
+    let otel_layer: Option<tracing_subscriber::layer::Identity> = None;
+    let guard = |log_file| TracingGuard {
+        _log_file: log_file,
+        #[cfg(feature = "otel")]
+        _otel: otel_guard,
+    };
 
-    // Calculate the duration to sleep
-    let duration_to_sleep = target_time - Local::now();
-    if duration_to_sleep.num_seconds() > 0 {
-        let message = format!(
-            "Sunrise local is {}. Sleeping for {} seconds until {} before turning off morning lights.",
-            sunrise_local,
-            duration_to_sleep.num_seconds(),
-            target_time
+    let Ok(path) = std::env::var("LOG_FILE") else {
+        tracing_subscriber::registry()
+            .with(otel_layer)
+            .with(stderr_layer)
+            .with(csv_layer)
+            .init();
+        return Ok(guard(None));
+    };
+    let path = Path::new(&path);
+    let rotation = match std::env::var("LOG_ROTATION").as_deref() {
+        Ok("hourly") => Rotation::HOURLY,
+        Ok("never") => Rotation::NEVER,
+        Ok("daily") | Err(_) => Rotation::DAILY,
+        Ok(other) => anyhow::bail!(
+            "LOG_ROTATION must be hourly, daily or never, got {:?}",
+            other
+        ),
+    };
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("LOG_FILE {:?} has no file name", path))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+    let appender = RollingFileAppender::new(rotation, directory, file_name);
+    let (writer, file_guard) = tracing_appender::non_blocking(appender);
+    let file_layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_filter(
+            filter::Targets::new()
+                .with_target(EVENT_LOG_TARGET, Level::INFO)
+                .with_default(Level::WARN),
         );
-        println!("{}", message);
-        log_light_event(&client, "Info", &message, "All").await?;
-        sleep(Duration::from_secs(duration_to_sleep.num_seconds() as u64)).await;
+    tracing_subscriber::registry()
+        .with(otel_layer)
+        .with(stderr_layer)
+        .with(csv_layer)
+        .with(file_layer)
+        .init();
+    Ok(guard(Some(file_guard)))
+}
+
+async fn run(cli: &Cli, config: &mut Config, client: Option<&Client>) -> anyhow::Result<()> {
+    let clock: Box<dyn Clock> = match cli.now {
+        Some(now) => {
+            config.console.warn(&format!(
+                "--now is for testing: using {} as the current time",
+                now
+            ));
+            config.daemon = false;
+            Box::new(FakeClock::new(now.with_timezone(&Local)))
+        }
+        None => Box::new(SystemClock),
+    };
+    let clock = clock.as_ref();
+    if cli.turn_on || cli.turn_off {
+        let command = match cli.command.clone() {
+            Some(command) => command,
+            None if cli.turn_on => LightCommand::on(),
+            None => LightCommand::off(),
+        };
+        run_manual(config, client, command, &cli.rooms).await
+    } else if cli.test_notifiers {
+        run_test_notifiers(config, client).await
+    } else if cli.check_schema {
+        run_check_schema(config, client).await
+    } else if let Some(name) = &cli.identify {
+        run_identify(config, client, name, cli.blinks).await
+    } else if cli.prune_logs {
+        let older_than = cli
+            .older_than
+            .map(Into::into)
+            .or(config.log_retention)
+            .context("--prune-logs needs --older-than or LOG_RETENTION")?;
+        run_prune(config, client, older_than).await
+    } else if let Some(days) = cli.forecast {
+        run_forecast(config, client, clock, days).await
+    } else if cli.replay_failures {
+        run_replay(config, client).await
+    } else if cli.next {
+        run_next(config, client, clock).await
+    } else if cli.discover {
+        run_discover(config, client, cli.save).await
+    } else if config.observe_only {
+        if config.daemon {
+            run_daemon(config, client).await
+        } else {
+            run_observe(config, client, clock).await
+        }
     } else {
-        let message = format!(
-            "It is already close enough to sunrise. Sunrise local today is {}. Turning lights off immediately.",
-            sunrise_local.format("%Y-%m-%d %H:%M:%S")
-        );
-        println!("{}", message);
-        log_light_event(&client, "Info", &message, "All").await?;
-    }
-
-    // Turn the lights off
-    let payload_off = r#"{"method":"setPilot","params":{"state":false}}"#;
-    for light in &wiz_lights {
-        match send_udp_packet(&light.host_id, payload_off).await {
-            Ok(_) => {
-                let severity: &str = "Info";
-                let message: String =
-                    format!("Light {} at {} turned off!", light.name, light.host_id);
-                println!("SUCCESS: {}", message);
-                log_light_event(&client, severity, &message, &light.name).await?;
+        wait_for_bind(config.udp_bind())
+            .await
+            .context("binding a UDP socket")?;
+        if config.udp_self_test {
+            if let Err(e) = udp_self_test(config.udp_bind()).await {
+                let message = format!(
+                    "UDP self-test failed, so local networking cannot send to lights: {}",
+                    e
+                );
+                config.console.error(&message);
+                log_event(
+                    &config.event_log,
+                    client,
+                    STARTUP_JOB,
+                    "Error",
+                    &message,
+                    "All",
+                )
+                .await
+                .context("logging self-test failure")?;
+                return Err(e).context("UDP self-test");
             }
-            Err(e) => {
-                let severity: &str = "Error";
+        }
+        if config.wait_for_lights {
+            wait_for_lights(config, client)
+                .await
+                .context("waiting for lights")?;
+        }
+        if !config.daemon {
+            return run_once(config, client, clock).await;
+        }
+        let config = &*config;
+        let server = async {
+            match (config.webhook_port, &config.webhook_secret) {
+                (Some(port), Some(secret)) => serve_webhook(config, client, port, secret)
+                    .await
+                    .context("serving inbound webhook"),
+                _ => Ok(()),
+            }
+        };
+        let keepalive = async {
+            if let Some(interval) = config.keepalive {
+                keepalive(config, client, interval).await;
+            }
+            Ok(())
+        };
+        futures::try_join!(run_daemon(config, client), server, keepalive).map(|_| ())
+    }
+}
+
+async fn run_once(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let mut attempt = 0;
+    // Replaces `client` when the connection dropped while waiting
+    let mut reconnected = None;
+    let (plan, report) = loop {
+        match plan_and_execute(config, client, clock, &mut reconnected).await {
+            Err(e) if attempt < config.run_retries && !config.daemon => {
+                attempt += 1;
                 let message = format!(
-                    "Failed to turn off light {} at {}: {}",
-                    light.name, light.host_id, e
+                    "Run attempt {} of {} failed: {:#}. Retrying in {}s.",
+                    attempt,
+                    config.run_retries + 1,
+                    e,
+                    config.run_retry_delay.as_secs()
                 );
-                println!("ERROR: {}", message);
-                log_light_event(&client, severity, &message, &light.name).await?;
+                config.console.warn(&message);
+                let current = reconnected.as_ref().or(client);
+                if let Err(log_err) =
+                    log_light_event(&config.event_log, current, "Warn", &message, "All").await
+                {
+                    config
+                        .console
+                        .error(&format!("failed to log run retry: {}", log_err));
+                }
+                sleep(config.run_retry_delay).await;
+            }
+            result => break result?,
+        }
+    };
+    let client = reconnected.as_ref().or(client);
+    print_report(config, &report)?;
+    record_summary(config, client, &plan, &report, started.elapsed()).await;
+
+    let failures = report.failures();
+    if failures == 0 || config.daemon {
+        return Ok(());
+    }
+    let summary = format!(
+        "{} of {} lights failed (FAIL_THRESHOLD {})",
+        failures,
+        report.results.len(),
+        config.fail_threshold
+    );
+    if config
+        .fail_threshold
+        .exceeded(failures, report.results.len())
+    {
+        anyhow::bail!(summary);
+    }
+    let message = format!("{}; exiting successfully.", summary);
+    config.console.warn(&message);
+    log_event(
+        &config.event_log,
+        client,
+        &report.job,
+        "Warn",
+        &message,
+        "All",
+    )
+    .await
+    .context("logging tolerated failures")?;
+    Ok(())
+}
+
+async fn plan_and_execute(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+    reconnected: &mut Option<Client>,
+) -> anyhow::Result<(RunPlan, RunReport)> {
+    let current = reconnected.as_ref().or(client);
+    let mut plan = plan_run(config, current, clock)
+        .await
+        .context("planning run")?;
+    if config.run_now {
+        plan.job = MANUAL_JOB.to_string();
+    }
+    let report = execute_reconnecting(&plan, config, client, clock, reconnected).await?;
+    Ok((plan, report))
+}
+
+/// Waits for the plan's target, then switches through a fresh connection
+/// stored in `reconnected` if the current one dropped meanwhile.
+async fn execute_reconnecting(
+    plan: &RunPlan,
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+    reconnected: &mut Option<Client>,
+) -> anyhow::Result<RunReport> {
+    let current = reconnected.as_ref().or(client);
+    if let Some(report) = wait_for_target(plan, config, current, clock)
+        .await
+        .context("executing run")?
+    {
+        return Ok(report);
+    }
+    // Hours may have passed on one idle connection, which the server or a
+    // NAT can drop in the meantime
+    let dropped = match current {
+        Some(current) => !is_alive(current).await,
+        None => false,
+    };
+    if dropped {
+        config
+            .console
+            .warn("database connection dropped while waiting; reconnecting");
+        let (fresh, _connection) = connect(config).await.context("reconnecting to database")?;
+        *reconnected = Some(fresh);
+    }
+    let client = reconnected.as_ref().or(client);
+    switch_planned(plan, config, client)
+        .await
+        .context("executing run")
+}
+
+/// Records the run's `run_summary` row. The lights are already switched, so
+/// a failure is only reported.
+async fn record_summary(
+    config: &Config,
+    client: Option<&Client>,
+    plan: &RunPlan,
+    report: &RunReport,
+    duration: Duration,
+) {
+    let Some(client) = client else {
+        return;
+    };
+    let result = log_run_summary(
+        client,
+        report,
+        plan.event_time.to_utc(),
+        plan.target.to_utc(),
+        duration,
+    )
+    .await;
+    if let Err(e) = result {
+        config
+            .console
+            .warn(&format!("Could not record run summary: {}", e));
+    }
+}
+
+/// Hands a failed run to the notifier, which follows the event bus. Run
+/// reports reach it the same way, published as each run completes.
+fn notify_failure(config: &Config, job: &str, message: &str) {
+    config.events.publish(RunEvent::Failed {
+        job: job.to_string(),
+        error: message.to_string(),
+    });
+}
+
+/// Sends a synthetic run report and failure through each notifier in turn.
+/// No lights are switched; the log only records that a test was sent.
+async fn run_test_notifiers(config: &Config, client: Option<&Client>) -> anyhow::Result<()> {
+    let notifiers = config.notifier.members();
+    if notifiers.is_empty() {
+        config.console.warn("No notifiers are configured.");
+        return Ok(());
+    }
+
+    let report = RunReport {
+        job: TEST_JOB.to_string(),
+        ..RunReport::default()
+    };
+    let mut failed = 0;
+    for notifier in &notifiers {
+        let result = match notifier.on_run_complete(&report).await {
+            Ok(()) => notifier.on_failure("test notification").await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => config.console.success(&format!("{}: ok", notifier.name())),
+            Err(e) => {
+                failed += 1;
+                config.console.error(&format!("{}: {}", notifier.name(), e));
             }
         }
     }
 
+    let message = format!(
+        "Sent test notifications: {} of {} notifiers succeeded.",
+        notifiers.len() - failed,
+        notifiers.len()
+    );
+    config.console.info(&message);
+    log_event(&config.event_log, client, TEST_JOB, "Info", &message, "All")
+        .await
+        .context("logging notifier test")?;
+    if failed > 0 {
+        anyhow::bail!("{} notifiers failed", failed);
+    }
     Ok(())
 }
 
-async fn fetch_wiz_lights(client: &Client) -> Result<Vec<WizLight>, Box<dyn std::error::Error>> {
-    let rows = client
-        .query("SELECT host_id, name FROM machine", &[])
-        .await?;
+/// Resends the failed commands of the last recorded run to the same lights,
+/// logged as a separate `Replay` run.
+async fn run_replay(config: &Config, client: Option<&Client>) -> anyhow::Result<()> {
+    let client = client.context("--replay-failures requires a database connection")?;
+    let failures = load_last_run_failures(client)
+        .await
+        .context("loading last run results")?;
+    if failures.is_empty() {
+        config
+            .console
+            .info("The last run had no failures; nothing to replay.");
+        return Ok(());
+    }
 
-    let network_id: String = env::var("NETWORK_ID").expect("NETWORK_ID not set");
+    let mut lights = load_lights(config, Some(client), REPLAY_JOB)
+        .await
+        .context("loading lights")?;
+    let mut planned = Vec::new();
+    for (name, command) in failures {
+        match lights.iter().position(|light| light.name == name) {
+            Some(index) => planned.push(PlannedLight {
+                light: lights.swap_remove(index),
+                command,
+                sequence: None,
+            }),
+            None => {
+                let message = format!(
+                    "Light {} from the last run no longer exists; not replayed.",
+                    name
+                );
+                config.console.warn(&message);
+                log_event(
+                    &config.event_log,
+                    Some(client),
+                    REPLAY_JOB,
+                    "Warn",
+                    &message,
+                    &name,
+                )
+                .await
+                .context("logging replay")?;
+            }
+        }
+    }
 
-    let mut wiz_lights = Vec::new();
-    for row in rows {
-        let host_id: String = row.get("host_id");
-        let name: String = row.get("name");
-        wiz_lights.push(WizLight {
-            host_id: format!("{}.{}:38899", network_id, host_id),
-            name,
+    let report = switch(REPLAY_JOB, &Targets::Lights(planned), config, Some(client))
+        .await
+        .context("replaying failures")?;
+    print_report(config, &report)
+}
+
+/// Sends `command` to every light, or to those in `rooms`, right away.
+async fn run_manual(
+    config: &Config,
+    client: Option<&Client>,
+    command: LightCommand,
+    rooms: &[String],
+) -> anyhow::Result<()> {
+    let mut lights = load_lights(config, client, MANUAL_JOB)
+        .await
+        .context("loading lights")?;
+    if !rooms.is_empty() {
+        let mut available: Vec<&str> = lights
+            .iter()
+            .filter_map(|light| light.room.as_deref())
+            .collect();
+        available.sort_unstable();
+        available.dedup();
+        let unknown: Vec<&str> = rooms
+            .iter()
+            .map(String::as_str)
+            .filter(|room| {
+                !available
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(room))
+            })
+            .collect();
+        if !unknown.is_empty() {
+            anyhow::bail!(
+                "unknown room {}; available rooms: {}",
+                unknown.join(", "),
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            );
+        }
+        lights.retain(|light| {
+            light
+                .room
+                .as_deref()
+                .is_some_and(|room| rooms.iter().any(|wanted| wanted.eq_ignore_ascii_case(room)))
         });
     }
 
-    Ok(wiz_lights)
+    let planned = lights
+        .into_iter()
+        .map(|light| PlannedLight {
+            light,
+            command: command.clone(),
+            sequence: None,
+        })
+        .collect();
+    let report = switch(MANUAL_JOB, &Targets::Lights(planned), config, client)
+        .await
+        .context("switching lights")?;
+    print_report(config, &report)?;
+    Ok(())
 }
 
-async fn fetch_sunrise_time() -> Result<DateTime<Utc>, SunriseError> {
-    let lat: f64 = env::var("LAT")
-        .expect("LAT not set")
-        .parse()
-        .expect("Invalid latitude value");
-    let lng: f64 = env::var("LNG")
-        .expect("LNG not set")
-        .parse()
-        .expect("Invalid longitude value");
+/// Pause between the toggles of an identify blink.
+const BLINK_INTERVAL: Duration = Duration::from_millis(600);
+
+/// How long `--identify` waits for the light to report its state.
+const IDENTIFY_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Toggles the named light `blinks` times, leaving it as it was found.
+async fn run_identify(
+    config: &Config,
+    client: Option<&Client>,
+    name: &str,
+    blinks: u32,
+) -> anyhow::Result<()> {
+    let lights = load_lights(config, client, MANUAL_JOB)
+        .await
+        .context("loading lights")?;
+    let light = lights
+        .iter()
+        .find(|light| light.name.eq_ignore_ascii_case(name))
+        .with_context(|| format!("no light named {:?}", name))?;
+    let addr = resolve(&light.host_id, config.resolve_timeout).await?;
+    // An unreadable state is treated as off, so the blinks end with it off
+    let was_on = query_udp(
+        config.udp_bind(),
+        addr,
+        GET_PILOT_REQUEST,
+        IDENTIFY_REPLY_TIMEOUT,
+    )
+    .await
+    .and_then(|reply| pilot_state(&reply))
+    .unwrap_or(false);
 
-    let url = format!(
-        "https://api.sunrise-sunset.org/json?lat={}&lng={}&formatted=0",
-        lat, lng
+    for toggle in 0..blinks * 2 {
+        let command = match (toggle % 2 == 0) != was_on {
+            true => LightCommand::on(),
+            false => LightCommand::off(),
+        };
+        send_udp_packet(
+            config.udp_bind(),
+            addr,
+            &command.payload_for(light.firmware_profile),
+        )
+        .await
+        .with_context(|| format!("blinking light {}", light.name))?;
+        sleep(BLINK_INTERVAL).await;
+    }
+
+    let message = format!(
+        "Blinked light {} at {} {} times to identify it.",
+        light.name, light.host_id, blinks
     );
+    config.console.success(&message);
+    log_event(
+        &config.event_log,
+        client,
+        MANUAL_JOB,
+        "Info",
+        &message,
+        &light.name,
+    )
+    .await
+    .context("logging identify")?;
+    Ok(())
+}
 
-    let resp = reqwest::get(&url)
-        .await?
-        .json::<SunriseSunsetResponse>()
+/// Deletes `log` rows older than `older_than` and reports how many went.
+async fn run_prune(
+    config: &Config,
+    client: Option<&Client>,
+    older_than: Duration,
+) -> anyhow::Result<()> {
+    let client = client.context("--prune-logs requires a database connection")?;
+    let removed = prune_logs(client, older_than)
+        .await
+        .context("pruning log table")?;
+    config.console.info(&format!(
+        "Removed {} log rows older than {}.",
+        removed,
+        humantime::format_duration(older_than)
+    ));
+    Ok(())
+}
+
+/// Reports every difference between the database schema and the one the
+/// queries expect, failing when there is any.
+async fn run_check_schema(config: &Config, client: Option<&Client>) -> anyhow::Result<()> {
+    let client = client.context("--check-schema requires a database connection")?;
+    let mismatches = check_schema(client)
+        .await
+        .context("reading information_schema")?;
+    if mismatches.is_empty() {
+        config
+            .console
+            .info("The machine and log tables match the expected schema.");
+        return Ok(());
+    }
+    for mismatch in &mismatches {
+        config.console.error(mismatch);
+    }
+    anyhow::bail!("{} schema mismatches", mismatches.len())
+}
+
+/// The valid `schedule` jobs, or the morning routine when none are
+/// configured.
+async fn enabled_jobs(client: Option<&Client>) -> anyhow::Result<Vec<Job>> {
+    let jobs = match client {
+        Some(client) => load_schedule(client).await.context("loading schedule")?,
+        None => Vec::new(),
+    };
+    Ok(if jobs.is_empty() {
+        vec![Job::morning()]
+    } else {
+        jobs.into_iter().filter_map(Result::ok).collect()
+    })
+}
+
+/// Prints when each of the day's jobs would run over the next `days` days.
+async fn run_forecast(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+    days: u32,
+) -> anyhow::Result<()> {
+    let jobs = enabled_jobs(client).await?;
+
+    let today = clock.now().date_naive();
+    // Days are fetched concurrently; a day's jobs share its solar times
+    let forecasts: Vec<Vec<_>> = stream::iter(today.iter_days().take(days as usize))
+        .map(|day| {
+            let jobs = &jobs;
+            async move {
+                let mut forecasts = Vec::new();
+                for job in jobs {
+                    let forecast = forecast_job(config, client, job, day)
+                        .await
+                        .with_context(|| format!("forecasting {} on {}", job.name, day))?;
+                    forecasts.push(forecast);
+                }
+                anyhow::Ok(forecasts)
+            }
+        })
+        .buffered(config.sunrise_concurrency.max(1))
+        .try_collect()
         .await?;
-    let sunrise_utc = resp.results.sunrise.parse::<DateTime<Utc>>()?;
-    Ok(sunrise_utc)
+    let forecasts: Vec<_> = forecasts.into_iter().flatten().collect();
+
+    if config.json {
+        let forecasts: Vec<_> = forecasts
+            .iter()
+            .map(|forecast| {
+                serde_json::json!({
+                    "day": forecast.day.to_string(),
+                    "job": forecast.job,
+                    "event": forecast.event.to_string(),
+                    "event_time": forecast.event_time.to_rfc3339(),
+                    "target": forecast.target.to_rfc3339(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(forecasts));
+        return Ok(());
+    }
+    for forecast in &forecasts {
+        println!(
+            "{}  {:<12} {} {}  -> {}",
+            forecast.day.format("%a %Y-%m-%d"),
+            forecast.job,
+            forecast.event,
+            forecast.event_time.format("%H:%M:%S"),
+            forecast.target.format("%H:%M:%S")
+        );
+    }
+    Ok(())
 }
 
-async fn send_udp_packet(addr: &str, payload: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let addr: SocketAddr = addr.parse()?;
-    socket.send_to(payload.as_bytes(), &addr).await?;
+/// Plans the morning run and prints its target time. Once today's target
+/// has passed, tomorrow's is shown instead. Planned in preview mode, so
+/// skipped lights and light addresses aren't recorded.
+async fn run_next(
+    config: &mut Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> anyhow::Result<()> {
+    config.preview = true;
+    let mut plan = plan_run(config, client, clock)
+        .await
+        .context("planning run")?;
+    let now = clock.now();
+    if config.date.is_none() && plan.sleep_duration(now).is_none() {
+        config.date = now.date_naive().succ_opt();
+        plan = plan_run(config, client, clock)
+            .await
+            .context("planning tomorrow's run")?;
+    }
+
+    let remaining = plan
+        .sleep_duration(now)
+        .unwrap_or_else(chrono::Duration::zero);
+    println!(
+        "Next action: {} at {} ({} seconds from now)",
+        plan.activity(),
+        plan.target.format("%Y-%m-%d %H:%M:%S %:z"),
+        remaining.num_seconds()
+    );
     Ok(())
 }
 
-async fn log_light_event(
-    client: &Client,
-    severity: &str,
-    message: &str,
-    machine: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let event_type = "Morning";
+/// Lists bulbs found by discovery and, with `save`, records new ones. Bulbs
+/// already in `machine` by MAC or address count as known.
+async fn run_discover(config: &Config, client: Option<&Client>, save: bool) -> anyhow::Result<()> {
+    let discovered = discover(
+        config.udp_bind(),
+        &config.network_id,
+        config.discovery_timeout,
+    )
+    .await
+    .context("discovering lights")?;
+    for light in &discovered {
+        println!("{}  {}", light.mac, light.addr);
+    }
+    if !save {
+        config
+            .console
+            .info(&format!("Discovered {} bulbs.", discovered.len()));
+        return Ok(());
+    }
 
-    client
-        .execute(
-            "INSERT INTO log (severity, message, machine, event_type) VALUES ($1, $2, $3, $4)",
-            &[&severity, &message, &machine, &event_type],
-        )
-        .await?;
+    let client = client.context("--save requires a database connection")?;
+    let known = fetch_wiz_lights(client, &config.network_id)
+        .await
+        .context("fetching lights from machine table")?;
+    let (mut added, mut already_known) = (0, 0);
+    for light in &discovered {
+        let known_by_addr = known
+            .iter()
+            .any(|known| known.host_id == light.addr.to_string());
+        if !known_by_addr
+            && save_discovered_light(client, light)
+                .await
+                .with_context(|| format!("saving light {}", light.mac))?
+        {
+            added += 1;
+        } else {
+            already_known += 1;
+        }
+    }
 
+    let message = format!(
+        "Discovered {} bulbs: {} added, {} already known.",
+        discovered.len(),
+        added,
+        already_known
+    );
+    config.console.info(&message);
+    log_light_event(&config.event_log, Some(client), "Info", &message, "All")
+        .await
+        .context("logging discovery")?;
     Ok(())
 }
+
+/// Prints the per-light results: JSON with `--json`, otherwise a table on
+/// the console.
+fn print_report(config: &Config, report: &RunReport) -> anyhow::Result<()> {
+    if config.json {
+        println!(
+            "{}",
+            serde_json::to_string(report).context("serializing run report")?
+        );
+        return Ok(());
+    }
+    if report.results.is_empty() {
+        return Ok(());
+    }
+
+    config.console.info(&format!("Summary for {}:", report.job));
+    for result in &report.results {
+        let status = match &result.outcome {
+            Outcome::Switched => "ok",
+            Outcome::AlreadyOff => "already off",
+            Outcome::Skipped(_) => "skipped",
+            Outcome::Failed(_) => "failed",
+            Outcome::TimedOut => "timed out",
+        };
+        config.console.info(&format!(
+            "  {:<24} {:<10} {:>6} ms",
+            result.name,
+            status,
+            result.latency.as_millis()
+        ));
+    }
+
+    // Per-room totals, in the order rooms first appear
+    let mut rooms: Vec<(&str, usize, usize)> = Vec::new();
+    for result in &report.results {
+        let Some(room) = result.room.as_deref() else {
+            continue;
+        };
+        let index = match rooms.iter().position(|(name, _, _)| *name == room) {
+            Some(index) => index,
+            None => {
+                rooms.push((room, 0, 0));
+                rooms.len() - 1
+            }
+        };
+        match result.outcome.is_failure() {
+            false => rooms[index].1 += 1,
+            true => rooms[index].2 += 1,
+        }
+    }
+    for (room, switched, failed) in rooms {
+        config.console.info(&format!(
+            "  {:<24} {} switched, {} failed",
+            room, switched, failed
+        ));
+    }
+    Ok(())
+}
+
+/// Runs every enabled `schedule` job for today in target order, or the
+/// morning routine when no schedule is configured. A failing job is logged
+/// without stopping the others.
+async fn run_day(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> anyhow::Result<()> {
+    let jobs = match client {
+        Some(client) => load_schedule(client).await.context("loading schedule")?,
+        None => Vec::new(),
+    };
+    if jobs.is_empty() {
+        return run_once(config, client, clock).await;
+    }
+
+    let mut plans = Vec::new();
+    for job in jobs {
+        let planned = match job {
+            Ok(job) => plan_job(config, client, clock, &job)
+                .await
+                .with_context(|| format!("planning job {}", job.name)),
+            Err(e) => Err(e.into()),
+        };
+        match planned {
+            Ok(plan) => plans.push(plan),
+            Err(e) => {
+                let message = format!("Skipping job: {:#}", e);
+                config.console.error(&message);
+                log_light_event(&config.event_log, client, "Error", &message, "All")
+                    .await
+                    .context("logging skipped job")?;
+            }
+        }
+    }
+
+    plans.sort_by_key(|plan| plan.target);
+    // Replaces `client` when the connection dropped while waiting
+    let mut reconnected = None;
+    for plan in &plans {
+        let started = Instant::now();
+        let result = execute_reconnecting(plan, config, client, clock, &mut reconnected).await;
+        let client = reconnected.as_ref().or(client);
+        match result {
+            Ok(report) => {
+                print_report(config, &report)?;
+                record_summary(config, client, plan, &report, started.elapsed()).await;
+            }
+            Err(e) => {
+                let message = format!("Job {} failed: {:#}", plan.job, e);
+                config.console.error(&message);
+                notify_failure(config, &plan.job, &message);
+                log_event(
+                    &config.event_log,
+                    client,
+                    &plan.job,
+                    "Error",
+                    &message,
+                    "All",
+                )
+                .await
+                .context("logging job failure")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `OBSERVE_ONLY`: records today's solar times and target for every job
+/// without loading or switching any lights.
+async fn run_observe(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> anyhow::Result<()> {
+    let jobs = enabled_jobs(client).await?;
+
+    let today = clock.now().date_naive();
+    for job in &jobs {
+        let forecast = forecast_job(config, client, job, today)
+            .await
+            .with_context(|| format!("observing {} on {}", job.name, today))?;
+        if let Some(client) = client {
+            if let Err(e) = store_observation(client, &forecast).await {
+                config
+                    .console
+                    .warn(&format!("Could not record observation: {}", e));
+            }
+        }
+        let message = format!(
+            "Observe only: {} local is {}; {} would run at {}.",
+            forecast.event.label(),
+            forecast.event_time.format("%Y-%m-%d %H:%M:%S"),
+            forecast.job,
+            forecast.target.format("%Y-%m-%d %H:%M:%S")
+        );
+        config.console.info(&message);
+        log_event(
+            &config.event_log,
+            client,
+            &job.name,
+            "Info",
+            &message,
+            "All",
+        )
+        .await
+        .context("logging observation")?;
+    }
+    Ok(())
+}
+
+/// Runs the day's jobs once per day, forever. Each run waits for its
+/// targets; afterwards the daemon sleeps until the next local day.
+async fn run_daemon(config: &Config, client: Option<&Client>) -> anyhow::Result<()> {
+    if !config.startup_delay.is_zero() {
+        let message = format!(
+            "Waiting STARTUP_DELAY_SECS of {}s before the first run.",
+            config.startup_delay.as_secs()
+        );
+        config.console.info(&message);
+        log_event(
+            &config.event_log,
+            client,
+            STARTUP_JOB,
+            "Info",
+            &message,
+            "All",
+        )
+        .await
+        .context("logging startup delay")?;
+        sleep(config.startup_delay).await;
+    }
+    let mut last_run_start: Option<Instant> = None;
+    loop {
+        // Guard against scheduling bugs that would start runs back to back
+        if let Some(last_run_start) = last_run_start {
+            let elapsed = last_run_start.elapsed();
+            if elapsed < config.min_run_interval {
+                let wait = config.min_run_interval - elapsed;
+                let message = format!(
+                    "Previous run started {}s ago, below MIN_RUN_INTERVAL of {}s. Waiting {}s before the next run.",
+                    elapsed.as_secs(),
+                    config.min_run_interval.as_secs(),
+                    wait.as_secs()
+                );
+                config.console.warn(&message);
+                log_light_event(&config.event_log, client, "Warn", &message, "All")
+                    .await
+                    .context("logging run interval floor")?;
+                sleep(wait).await;
+            }
+        }
+        last_run_start = Some(Instant::now());
+
+        let day = if config.observe_only {
+            run_observe(config, client, &SystemClock).await
+        } else {
+            run_day(config, client, &SystemClock).await
+        };
+        if let Err(e) = day {
+            let message = format!("Daemon run failed: {:#}", e);
+            config.console.error(&message);
+            notify_failure(config, MORNING_JOB, &message);
+            if let Err(log_err) =
+                log_light_event(&config.event_log, client, "Error", &message, "All").await
+            {
+                config
+                    .console
+                    .error(&format!("failed to log daemon run failure: {}", log_err));
+            }
+        }
+
+        if let (Some(retention), Some(client)) = (config.log_retention, client) {
+            match prune_logs(client, retention).await {
+                Ok(removed) => tracing::info!(removed, "pruned log table"),
+                Err(e) => config
+                    .console
+                    .warn(&format!("failed to prune log table: {}", e)),
+            }
+        }
+
+        log_sunrise_latency(config, client).await;
+        sleep(until_next_local_day()).await;
+    }
+}
+
+/// A daily glance at how the sunrise API has been responding, to spot it
+/// slowing down without a metrics setup.
+async fn log_sunrise_latency(config: &Config, client: Option<&Client>) {
+    let Some(summary) = latency_summary() else {
+        return;
+    };
+    let message = format!(
+        "Sunrise API latency over the last {} requests: p50 {}ms, p95 {}ms, max {}ms.",
+        summary.samples,
+        summary.p50.as_millis(),
+        summary.p95.as_millis(),
+        summary.max.as_millis()
+    );
+    config.console.info(&message);
+    if let Err(e) = log_light_event(&config.event_log, client, "Info", &message, "All").await {
+        config
+            .console
+            .warn(&format!("failed to log sunrise API latency: {}", e));
+    }
+}
+
+/// Time remaining until local midnight, when the next day's sunrise becomes
+/// available.
+fn until_next_local_day() -> Duration {
+    let now = Local::now();
+    let next_midnight = now
+        .date_naive()
+        .succ_opt()
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest());
+    match next_midnight {
+        Some(next_midnight) => (next_midnight - now).to_std().unwrap_or_default(),
+        None => Duration::from_secs(60 * 60),
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::redact_url;
+use crate::console::Console;
+use crate::error::AppError;
+use crate::events::{EventBus, RunEvent};
+use crate::plan::RunReport;
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
+
+/// Receives the outcome of each run, e.g. to forward it to a chat service.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn on_run_complete(&self, report: &RunReport) -> Result<(), AppError>;
+    async fn on_failure(&self, error: &str) -> Result<(), AppError>;
+    /// Names the notifier in `--test-notifiers` output.
+    fn name(&self) -> String;
+    /// The individual notifiers behind this one: itself, or those a
+    /// [`FanOut`] forwards to.
+    fn members(&self) -> Vec<&dyn Notifier>;
+}
+
+/// Used when no notifier is configured.
+pub struct NullNotifier;
+
+#[async_trait]
+impl Notifier for NullNotifier {
+    async fn on_run_complete(&self, _report: &RunReport) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn on_failure(&self, _error: &str) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "none".to_string()
+    }
+
+    fn members(&self) -> Vec<&dyn Notifier> {
+        Vec::new()
+    }
+}
+
+/// Forwards every notification to each notifier concurrently. All of them
+/// are attempted; the first error is returned.
+pub struct FanOut(pub Vec<Box<dyn Notifier>>);
+
+#[async_trait]
+impl Notifier for FanOut {
+    async fn on_run_complete(&self, report: &RunReport) -> Result<(), AppError> {
+        join_all(
+            self.0
+                .iter()
+                .map(|notifier| notifier.on_run_complete(report)),
+        )
+        .await
+        .into_iter()
+        .collect()
+    }
+
+    async fn on_failure(&self, error: &str) -> Result<(), AppError> {
+        join_all(self.0.iter().map(|notifier| notifier.on_failure(error)))
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        let names: Vec<String> = self.0.iter().map(|notifier| notifier.name()).collect();
+        names.join(", ")
+    }
+
+    fn members(&self) -> Vec<&dyn Notifier> {
+        self.0
+            .iter()
+            .flat_map(|notifier| notifier.members())
+            .collect()
+    }
+}
+
+/// POSTs JSON to `WEBHOOK_URL`: the run report, or `{"error": ...}`.
+pub struct WebhookNotifier {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(http: reqwest::Client, url: String) -> Self {
+        WebhookNotifier { http, url }
+    }
+
+    async fn post(&self, body: serde_json::Value) -> Result<(), AppError> {
+        self.http
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                AppError::Notify(format!(
+                    "webhook {}: {}",
+                    redact_url(&self.url),
+                    e.without_url()
+                ))
+            })?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn on_run_complete(&self, report: &RunReport) -> Result<(), AppError> {
+        self.post(json!(report)).await
+    }
+
+    async fn on_failure(&self, error: &str) -> Result<(), AppError> {
+        self.post(json!({ "error": error })).await
+    }
+
+    fn name(&self) -> String {
+        format!("webhook {}", redact_url(&self.url))
+    }
+
+    fn members(&self) -> Vec<&dyn Notifier> {
+        vec![self]
+    }
+}
+
+/// The notifiers enabled by their environment variables, or a
+/// [`NullNotifier`] when there are none.
+pub fn notifier_from_env(http: &reqwest::Client) -> Box<dyn Notifier> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Ok(url) = std::env::var("WEBHOOK_URL") {
+        notifiers.push(Box::new(WebhookNotifier::new(http.clone(), url)));
+    }
+    match notifiers.len() {
+        0 => Box::new(NullNotifier),
+        1 => notifiers.remove(0),
+        _ => Box::new(FanOut(notifiers)),
+    }
+}
+
+/// Subscribes `notifier` to runs on the bus: each completed run's report and
+/// each failure is forwarded, and the attempt published as a
+/// [`RunEvent::NotifierSent`]. `events` must be subscribed before the runs
+/// start. Once `done` fires, events already published are still sent before
+/// this returns. Notification problems are reported but never fail a run.
+pub async fn notify_events(
+    notifier: Arc<dyn Notifier>,
+    bus: EventBus,
+    mut events: broadcast::Receiver<RunEvent>,
+    console: Console,
+    mut done: oneshot::Receiver<()>,
+) {
+    loop {
+        // Biased, so pending events are drained before stopping
+        let event = tokio::select! {
+            biased;
+            event = events.recv() => event,
+            _ = &mut done => return,
+        };
+        let (job, result) = match event {
+            Ok(RunEvent::Completed { report }) => {
+                let result = notifier.on_run_complete(&report).await;
+                (report.job, result)
+            }
+            Ok(RunEvent::Failed { job, error }) => {
+                let result = notifier.on_failure(&error).await;
+                (job, result)
+            }
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "notifier fell behind; some runs were not notified");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        bus.publish(RunEvent::NotifierSent {
+            job,
+            success: result.is_ok(),
+        });
+        if let Err(e) = result {
+            console.warn(&e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records what it was told: the job of each report, or the error.
+    #[derive(Default)]
+    struct RecordingNotifier {
+        notified: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Notifier for RecordingNotifier {
+        async fn on_run_complete(&self, report: &RunReport) -> Result<(), AppError> {
+            self.notified.lock().unwrap().push(report.job.clone());
+            Ok(())
+        }
+
+        async fn on_failure(&self, error: &str) -> Result<(), AppError> {
+            self.notified.lock().unwrap().push(error.to_string());
+            Err(AppError::Notify("unreachable".to_string()))
+        }
+
+        fn name(&self) -> String {
+            "recording".to_string()
+        }
+
+        fn members(&self) -> Vec<&dyn Notifier> {
+            vec![self]
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_runs_published_before_stopping() {
+        let notifier = Arc::new(RecordingNotifier::default());
+        let bus = EventBus::default();
+        let mut sent = bus.subscribe();
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(notify_events(
+            notifier.clone(),
+            bus.clone(),
+            bus.subscribe(),
+            Console::default(),
+            stopped,
+        ));
+
+        bus.publish(RunEvent::Completed {
+            report: RunReport {
+                job: "Morning".to_string(),
+                ..RunReport::default()
+            },
+        });
+        bus.publish(RunEvent::Failed {
+            job: "Lamps".to_string(),
+            error: "no lights".to_string(),
+        });
+        stop.send(()).unwrap();
+        task.await.unwrap();
+
+        assert_eq!(*notifier.notified.lock().unwrap(), ["Morning", "no lights"]);
+        let mut results = Vec::new();
+        while let Ok(event) = sent.try_recv() {
+            if let RunEvent::NotifierSent { job, success } = event {
+                results.push((job, success));
+            }
+        }
+        assert_eq!(
+            results,
+            [("Morning".to_string(), true), ("Lamps".to_string(), false)]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use crate::error::AppError;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+
+const SERVICE_NAME: &str = "morning-lights-off";
+
+/// Flushes spans still queued for export when dropped.
+pub struct OtelGuard(SdkTracerProvider);
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("failed to flush OpenTelemetry spans: {}", e);
+        }
+    }
+}
+
+/// A tracer exporting spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set, for a `tracing_opentelemetry` layer. The exporter reads the
+/// endpoint and the other standard `OTEL_*` variables itself.
+pub fn tracer_from_env() -> Result<Option<(SdkTracer, OtelGuard)>, AppError> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| AppError::InvalidConfig(format!("OTEL_EXPORTER_OTLP_ENDPOINT: {}", e)))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    Ok(Some((tracer, OtelGuard(provider))))
+}
@@ -0,0 +1,1771 @@
+use crate::clock::Clock;
+use crate::config::{parse_flag, Config};
+use crate::controller::{CloudController, FallbackController, LightController, LocalController};
+use crate::db::{
+    load_control, load_daily_solar, load_recent_solar, log_event, record_light_address,
+    store_daily_solar, store_run_results, take_control,
+};
+use crate::discovery::{apply_discovered, discover};
+use crate::error::AppError;
+use crate::events::RunEvent;
+use crate::light::{
+    broadcast_address, fetch_wiz_lights, fetch_wiz_lights_http, filter_lights,
+    load_wiz_lights_file, order_lights, pilot_state, ramp_sequence, LightAction, LightCommand,
+    SequenceStep, WizLight, GET_PILOT_REQUEST,
+};
+use crate::schedule::{Job, SolarEvent, MORNING_JOB, STARTUP_JOB};
+use crate::season::{load_seasonal_rules, seasonal_command};
+#[cfg(unix)]
+use crate::sunrise::fetch_solar_times_unix;
+use crate::sunrise::{estimate_from_history, fetch_solar_times, SolarTimes};
+use crate::transport::{query_udp, resolve, send_udp_broadcast};
+use crate::weather::cloud_cover;
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc, Weekday};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::{sleep, sleep_until, timeout_at, Duration, Instant};
+use tokio_postgres::Client;
+use tracing::Instrument;
+
+/// A light together with the command it will be sent.
+#[derive(Clone, Debug)]
+pub struct PlannedLight {
+    pub light: WizLight,
+    /// The command sent, or the final step of `sequence`.
+    pub command: LightCommand,
+    /// The light's own sequence, run instead of the single command.
+    pub sequence: Option<Vec<SequenceStep>>,
+}
+
+/// Where a run sends its commands.
+#[derive(Clone, Debug)]
+pub enum Targets {
+    Lights(Vec<PlannedLight>),
+    /// A single best-effort datagram to the subnet broadcast address.
+    Broadcast {
+        addr: String,
+        command: LightCommand,
+    },
+}
+
+/// Everything a run will do, computed without touching any light.
+#[derive(Clone, Debug)]
+pub struct RunPlan {
+    /// The job being run, also used as the log `event_type`.
+    pub job: String,
+    pub event: SolarEvent,
+    pub event_time: DateTime<Local>,
+    pub target: DateTime<Local>,
+    /// The day's length, logged for context when the run starts. `None`
+    /// for a clock-time job, which doesn't look up the sun.
+    pub day_length: Option<chrono::Duration>,
+    pub targets: Targets,
+}
+
+impl RunPlan {
+    /// What the run does, e.g. "switching morning lights".
+    pub fn activity(&self) -> String {
+        if self.job == MORNING_JOB {
+            "switching morning lights".to_string()
+        } else {
+            format!("running job {}", self.job)
+        }
+    }
+
+    /// How long to wait at `now` before switching, to the millisecond, or
+    /// `None` once the target has passed.
+    pub fn sleep_duration(&self, now: DateTime<Local>) -> Option<chrono::Duration> {
+        let remaining = self.target - now;
+        (remaining > chrono::Duration::zero()).then_some(remaining)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum Outcome {
+    Switched,
+    /// Not sent because the light already reported off (`SKIP_IF_OFF`).
+    AlreadyOff,
+    /// Sending failed with an error `NONFATAL_IO_ERRORS` treats as transient.
+    Skipped(String),
+    Failed(String),
+    /// Not attempted, or not finished, before the run deadline.
+    TimedOut,
+}
+
+impl Outcome {
+    /// The `status` name used in JSON reports and `run_result` rows.
+    pub fn status(&self) -> &'static str {
+        match self {
+            Outcome::Switched => "switched",
+            Outcome::AlreadyOff => "already_off",
+            Outcome::Skipped(_) => "skipped",
+            Outcome::Failed(_) => "failed",
+            Outcome::TimedOut => "timed_out",
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Outcome::Failed(_) | Outcome::TimedOut)
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            Outcome::Skipped(error) | Outcome::Failed(error) => Some(error),
+            Outcome::Switched | Outcome::AlreadyOff | Outcome::TimedOut => None,
+        }
+    }
+}
+
+/// Outcome of sending a command to one light (or to the broadcast address).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LightResult {
+    pub name: String,
+    pub host_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    pub command: LightCommand,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    /// Time spent sending, including any wait for a reply.
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Duration,
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// Why a run sent nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// A daemon run found today's target already past.
+    TargetPassed,
+    Paused,
+    /// Skipped once by the `snooze` control row.
+    Snoozed,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RunReport {
+    pub job: String,
+    /// When sending started; identifies the run in `run_result` and
+    /// `run_summary`. `None` for a skipped run.
+    #[serde(skip)]
+    pub run_at: Option<DateTime<Utc>>,
+    pub skipped: Option<SkipReason>,
+    pub results: Vec<LightResult>,
+}
+
+impl RunReport {
+    /// Lights that failed or timed out.
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.outcome.is_failure())
+            .count()
+    }
+}
+
+/// How many failed lights a one-shot run tolerates before exiting non-zero.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailThreshold {
+    Count(usize),
+    /// Percentage of the lights in the run.
+    Percent(f64),
+}
+
+impl Default for FailThreshold {
+    /// Any failure fails the run.
+    fn default() -> Self {
+        FailThreshold::Count(0)
+    }
+}
+
+impl FailThreshold {
+    pub fn exceeded(self, failures: usize, total: usize) -> bool {
+        match self {
+            FailThreshold::Count(max) => failures > max,
+            FailThreshold::Percent(max) => {
+                total > 0 && failures as f64 * 100.0 / total as f64 > max
+            }
+        }
+    }
+}
+
+impl fmt::Display for FailThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailThreshold::Count(max) => write!(f, "{}", max),
+            FailThreshold::Percent(max) => write!(f, "{}%", max),
+        }
+    }
+}
+
+/// Parses an absolute count such as `1` or a percentage such as `25%`.
+impl FromStr for FailThreshold {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            AppError::InvalidConfig(format!(
+                "FAIL_THRESHOLD must be a count or a percentage, got {:?}",
+                s
+            ))
+        };
+        match s.trim().strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent: &f64| (0.0..=100.0).contains(percent))
+                .map(FailThreshold::Percent)
+                .ok_or_else(invalid),
+            None => s
+                .trim()
+                .parse()
+                .map(FailThreshold::Count)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Plans the built-in morning routine. See [`plan_job`].
+pub async fn plan_run(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> Result<RunPlan, AppError> {
+    plan_job(config, client, clock, &Job::morning()).await
+}
+
+/// Resolves the solar times for `config.date` (default: today), the job's
+/// target time and the command for every light. Nothing is sent; the solar
+/// times are cached in `daily_solar`.
+pub async fn plan_job(
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+    job: &Job,
+) -> Result<RunPlan, AppError> {
+    let today = config.date.unwrap_or_else(|| clock.now().date_naive());
+    let mut targets = if config.broadcast {
+        Targets::Broadcast {
+            addr: broadcast_address(&config.network_id),
+            command: job
+                .command
+                .clone()
+                .unwrap_or_else(|| config.morning_command.clone()),
+        }
+    } else {
+        let wiz_lights = load_lights(config, client, &job.name).await?;
+        let seasonal_rules = match (client, &job.command) {
+            (Some(client), None) => load_seasonal_rules(client).await?,
+            _ => Vec::new(),
+        };
+        let month = today.month();
+        let lights = wiz_lights
+            .into_iter()
+            .map(|light| {
+                // Precedence: the job's explicit command, the light's
+                // sequence, a seasonal rule, then the light's action
+                let sequence = light.sequence.clone().filter(|_| job.command.is_none());
+                let seasonal = seasonal_command(&seasonal_rules, month, &light);
+                let command = match (&job.command, &sequence, seasonal, light.action) {
+                    (Some(command), _, _, _) => command.clone(),
+                    (None, Some(steps), _, _) => steps[steps.len() - 1].command.clone(),
+                    (None, None, Some(command), _) => command.clone(),
+                    (None, None, None, LightAction::Off) => config.morning_command.clone(),
+                    (None, None, None, LightAction::On) => LightCommand::on(),
+                };
+                PlannedLight {
+                    light,
+                    command,
+                    sequence,
+                }
+            })
+            .collect();
+        Targets::Lights(lights)
+    };
+
+    let times = job_solar_times(config, client, job, today).await?;
+    let Forecast {
+        event_time,
+        mut target,
+        offset_minutes,
+        weekend_offset,
+        ..
+    } = forecast_from(config, job, today, times.as_ref());
+    if config.weekend_offset_minutes.is_some() && job.name == MORNING_JOB {
+        let message = format!(
+            "Using the {} offset of {} minutes on {}.",
+            if weekend_offset { "weekend" } else { "weekday" },
+            offset_minutes,
+            event_time.format("%A %Y-%m-%d")
+        );
+        config.console.info(&message);
+        log_event(
+            &config.event_log,
+            client,
+            &job.name,
+            "Info",
+            &message,
+            "All",
+        )
+        .await?;
+    }
+    if job.command.as_ref().is_none_or(|command| !command.state) {
+        target += cloudy_delay(config, client, &job.name, today, target).await?;
+    }
+
+    if let (Some(until), Some(command), Targets::Lights(lights)) =
+        (job.ramp_until, &job.command, &mut targets)
+    {
+        let ramp_end = until
+            .time(today, times.as_ref())
+            .expect("solar times are loaded for ramp_until")
+            .with_timezone(&Local);
+        let duration = (ramp_end - target)
+            .to_std()
+            .ok()
+            .filter(|duration| !duration.is_zero())
+            .ok_or_else(|| AppError::InvalidJob {
+                name: job.name.clone(),
+                reason: format!("ramp ends at {}, before it starts at {}", ramp_end, target),
+            })?;
+        let ramp = ramp_sequence(command, duration);
+        for planned in lights {
+            planned.sequence = Some(ramp.clone());
+        }
+    }
+
+    config.events.publish(RunEvent::Planned {
+        job: job.name.clone(),
+        target,
+        lights: match &targets {
+            Targets::Lights(lights) => lights.len(),
+            Targets::Broadcast { .. } => 0,
+        },
+    });
+    Ok(RunPlan {
+        job: job.name.clone(),
+        event: job.event,
+        event_time,
+        target,
+        day_length: times.map(|times| times.day_length),
+        targets,
+    })
+}
+
+/// The extra delay, `CLOUDY_EXTRA_MINUTES`, before lights are turned off
+/// at `target` on a cloudy morning. Zero without `WEATHER_API_KEY`, on a
+/// clear morning or when the forecast can't be fetched.
+async fn cloudy_delay(
+    config: &Config,
+    client: Option<&Client>,
+    job: &str,
+    day: NaiveDate,
+    target: DateTime<Local>,
+) -> Result<chrono::Duration, AppError> {
+    let Some(api_key) = &config.weather_api_key else {
+        return Ok(chrono::Duration::zero());
+    };
+    let cover = cloud_cover(
+        &config.http,
+        api_key,
+        config.lat,
+        config.lng,
+        day,
+        target.with_timezone(&Utc),
+        &config.weather_cache,
+    )
+    .await;
+    match cover {
+        Ok(percent) if percent >= config.cloudy_threshold => {
+            let message = format!(
+                "Cloud cover {}% at {} is at least {}%; turning lights off {} minutes later.",
+                percent,
+                target.format("%H:%M"),
+                config.cloudy_threshold,
+                config.cloudy_extra_minutes
+            );
+            config.console.info(&message);
+            log_event(&config.event_log, client, job, "Info", &message, "All").await?;
+            Ok(chrono::Duration::minutes(config.cloudy_extra_minutes))
+        }
+        Ok(_) => Ok(chrono::Duration::zero()),
+        Err(e) => {
+            let message = format!("{}; keeping the usual offset.", e);
+            config.console.warn(&message);
+            log_event(&config.event_log, client, job, "Warn", &message, "All").await?;
+            Ok(chrono::Duration::zero())
+        }
+    }
+}
+
+/// When a job would run on a given day.
+#[derive(Clone, Debug)]
+pub struct Forecast {
+    pub day: NaiveDate,
+    pub job: String,
+    pub event: SolarEvent,
+    pub event_time: DateTime<Local>,
+    pub target: DateTime<Local>,
+    /// Minutes from the event to the target, before any cloudy delay.
+    pub offset_minutes: i64,
+    /// Whether `offset_minutes` is `OFFSET_MINUTES_WEEKEND`.
+    pub weekend_offset: bool,
+}
+
+/// Computes a job's target for `day` from its solar times alone, without
+/// loading lights. Used by `--forecast`.
+pub async fn forecast_job(
+    config: &Config,
+    client: Option<&Client>,
+    job: &Job,
+    day: NaiveDate,
+) -> Result<Forecast, AppError> {
+    let times = job_solar_times(config, client, job, day).await?;
+    Ok(forecast_from(config, job, day, times.as_ref()))
+}
+
+/// The day's solar times if the job is anchored to, or ramps until, a
+/// solar event. A clock-time job never calls the sunrise API.
+async fn job_solar_times(
+    config: &Config,
+    client: Option<&Client>,
+    job: &Job,
+    day: NaiveDate,
+) -> Result<Option<SolarTimes>, AppError> {
+    if job.event.is_solar() || job.ramp_until.is_some_and(SolarEvent::is_solar) {
+        return Ok(Some(solar_times_for(config, client, &job.name, day).await?));
+    }
+    Ok(None)
+}
+
+fn forecast_from(
+    config: &Config,
+    job: &Job,
+    day: NaiveDate,
+    times: Option<&SolarTimes>,
+) -> Forecast {
+    let event_time = job
+        .event
+        .time(day, times)
+        .expect("solar times are loaded for solar events");
+    let weekend = weekend_offset(config, job, event_time);
+    let offset_minutes = weekend.unwrap_or(job.offset_minutes);
+    let event_time = event_time.with_timezone(&Local);
+    Forecast {
+        day,
+        job: job.name.clone(),
+        event: job.event,
+        event_time,
+        target: event_time + chrono::Duration::minutes(offset_minutes),
+        offset_minutes,
+        weekend_offset: weekend.is_some(),
+    }
+}
+
+/// `OFFSET_MINUTES_WEEKEND`, which replaces the built-in morning job's
+/// offset when its event falls on a Saturday or Sunday in `TIMEZONE` (or
+/// local time without one).
+fn weekend_offset(config: &Config, job: &Job, event_time: DateTime<Utc>) -> Option<i64> {
+    let offset = config
+        .weekend_offset_minutes
+        .filter(|_| job.name == MORNING_JOB)?;
+    let weekday = match config.timezone {
+        Some(tz) => event_time.with_timezone(&tz).weekday(),
+        None => event_time.with_timezone(&Local).weekday(),
+    };
+    matches!(weekday, Weekday::Sat | Weekday::Sun).then_some(offset)
+}
+
+/// Loads the lights given with `--light`, or else from `LIGHTS_URL`, the
+/// database or `LIGHTS_FILE`, drops those filtered out by `INCLUDE_LIGHTS` and
+/// `EXCLUDE_LIGHTS` and resolves MAC addresses. Skips are logged under `job`.
+pub async fn load_lights(
+    config: &Config,
+    client: Option<&Client>,
+    job: &str,
+) -> Result<Vec<WizLight>, AppError> {
+    let wiz_lights = source_lights(config, client).await?;
+    let loaded = wiz_lights.len();
+    // A bad query must not send commands to every address it returns
+    if wiz_lights.len() > config.max_lights {
+        return Err(AppError::TooManyLights {
+            count: wiz_lights.len(),
+            max: config.max_lights,
+        });
+    }
+    let (mut wiz_lights, skipped) = filter_lights(
+        wiz_lights,
+        config.include_lights.as_deref(),
+        &config.exclude_lights,
+    );
+    for (light, reason) in skipped {
+        let message = format!(
+            "Light {} at {} skipped: {}.",
+            light.name, light.host_id, reason
+        );
+        log_skip(config, client, job, &message, &light.name).await?;
+    }
+    if wiz_lights.is_empty() && config.error_on_empty {
+        let cause = match loaded {
+            0 => {
+                "the light source returned none; check the machine table, LIGHTS_FILE or LIGHTS_URL"
+                    .to_string()
+            }
+            _ => format!(
+                "INCLUDE_LIGHTS or EXCLUDE_LIGHTS filtered out all {} lights",
+                loaded
+            ),
+        };
+        let message = format!(
+            "No lights to switch: {}. Set ERROR_ON_EMPTY=false if this is intended.",
+            cause
+        );
+        log_event(&config.event_log, client, job, "Error", &message, "All").await?;
+        return Err(AppError::NoLights(cause));
+    }
+    if config.shuffle_order {
+        let mut rng = match config.shuffle_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        wiz_lights.shuffle(&mut rng);
+    } else {
+        order_lights(&mut wiz_lights, config.order_by);
+    }
+    if wiz_lights.iter().any(|light| light.mac.is_some()) {
+        let discovered = discover(
+            config.udp_bind(),
+            &config.network_id,
+            config.discovery_timeout,
+        )
+        .await?;
+        apply_discovered(&mut wiz_lights, &discovered);
+
+        // DHCP churn is otherwise invisible, since discovery follows the bulb
+        if let Some(client) = client.filter(|_| !config.preview) {
+            for light in wiz_lights.iter().filter(|light| light.mac.is_some()) {
+                // Best-effort: the addresses are only used for this warning
+                let previous = match record_light_address(client, &light.name, &light.host_id).await
+                {
+                    Ok(previous) => previous,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "could not record light addresses");
+                        break;
+                    }
+                };
+                if let Some(previous) = previous.filter(|previous| *previous != light.host_id) {
+                    let message = format!(
+                        "Light {} moved from {} to {} since the last run.",
+                        light.name, previous, light.host_id
+                    );
+                    config.console.warn(&message);
+                    log_event(
+                        &config.event_log,
+                        Some(client),
+                        job,
+                        "Warn",
+                        &message,
+                        &light.name,
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+    Ok(wiz_lights)
+}
+
+/// The lights from the first configured source: `--light`, `LIGHTS_URL`,
+/// the database or `LIGHTS_FILE`, before any filtering.
+async fn source_lights(
+    config: &Config,
+    client: Option<&Client>,
+) -> Result<Vec<WizLight>, AppError> {
+    Ok(match client {
+        _ if !config.lights.is_empty() => config
+            .lights
+            .iter()
+            .map(|addr| WizLight::from_addr(addr, &config.network_id))
+            .collect(),
+        _ if config.lights_url.is_some() => {
+            let url = config.lights_url.as_deref().unwrap_or_default();
+            fetch_wiz_lights_http(&config.http, url, &config.network_id, &config.lights_cache)
+                .await?
+        }
+        Some(client) => fetch_wiz_lights(client, &config.network_id).await?,
+        None => {
+            let path = config.lights_file.as_deref().ok_or_else(|| {
+                AppError::InvalidConfig(
+                    "REQUIRE_DB is false but LIGHTS_FILE is not set".to_string(),
+                )
+            })?;
+            load_wiz_lights_file(path, &config.network_id)?
+        }
+    })
+}
+
+/// Returns the day's solar times, reusing the copy already fetched by this
+/// process or recorded in `daily_solar` so every job, and restarts, share
+/// one API call per day. While the circuit breaker is open
+/// the API is skipped and only the drift fallback is tried.
+async fn solar_times_for(
+    config: &Config,
+    client: Option<&Client>,
+    job: &str,
+    day: NaiveDate,
+) -> Result<SolarTimes, AppError> {
+    if let Some(times) = config.solar_cache.get(day) {
+        return Ok(times);
+    }
+    if let Some(client) = client {
+        if let Some(times) = load_daily_solar(client, day).await? {
+            config.solar_cache.insert(day, times);
+            return Ok(times);
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.sunrise_socket {
+        match fetch_solar_times_unix(path, config.lat, config.lng, day).await {
+            Ok(times) => {
+                config.solar_cache.insert(day, times);
+                if let Some(client) = client {
+                    store_daily_solar(client, day, &times).await?;
+                }
+                return Ok(times);
+            }
+            Err(e) => {
+                let message = format!(
+                    "Sunrise lookup from {} failed: {}; trying the sunrise API.",
+                    path, e
+                );
+                config.console.warn(&message);
+                log_event(&config.event_log, client, job, "Warn", &message, "All").await?;
+            }
+        }
+    }
+
+    let breaker = &config.sunrise_breaker;
+    let error = if let Some(reopens) = breaker.open_until() {
+        let message = format!(
+            "Sunrise API circuit breaker is open; not calling it for another {}s.",
+            reopens.saturating_duration_since(Instant::now()).as_secs()
+        );
+        config.console.warn(&message);
+        log_event(&config.event_log, client, job, "Warn", &message, "All").await?;
+        AppError::BreakerOpen(reopens.saturating_duration_since(Instant::now()))
+    } else {
+        match fetch_solar_times(
+            &config.http,
+            config.lat,
+            config.lng,
+            Some(day),
+            config.sunrise_retries,
+            config.timezone,
+        )
+        .await
+        {
+            Ok(times) => {
+                breaker.record_success();
+                config.solar_cache.insert(day, times);
+                if let Some(client) = client {
+                    store_daily_solar(client, day, &times).await?;
+                }
+                return Ok(times);
+            }
+            Err(e) => {
+                let message = format!("Sunrise lookup failed: {}", e);
+                config.console.error(&message);
+                log_event(&config.event_log, client, job, "Error", &message, "All").await?;
+                if breaker.record_failure() {
+                    let message = format!(
+                        "Sunrise API failed {} times in a row; pausing calls for {}s.",
+                        breaker.threshold(),
+                        breaker.cooldown().as_secs()
+                    );
+                    config.console.warn(&message);
+                    log_event(&config.event_log, client, job, "Warn", &message, "All").await?;
+                }
+                e.into()
+            }
+        }
+    };
+
+    // Last resort: extrapolate from the days already recorded
+    if let (true, Some(client)) = (config.drift_fallback, client) {
+        let history = load_recent_solar(client, day, 2).await?;
+        if let Some(times) = estimate_from_history(day, &history) {
+            let message = format!(
+                "Using estimated sunrise {} extrapolated from {} (DRIFT_FALLBACK).",
+                times.sunrise.with_timezone(&Local),
+                history[0].0
+            );
+            config.console.warn(&message);
+            log_event(
+                &config.event_log,
+                Some(client),
+                job,
+                "Warn",
+                &message,
+                "All",
+            )
+            .await?;
+            // Not cached, so the next attempt still queries the API
+            return Ok(times);
+        }
+    }
+    Err(error)
+}
+
+/// Waits for the plan's target time, then sends every command and logs the
+/// outcome. When the target has already passed, a one-shot run switches the
+/// lights immediately while a daemon run skips the day.
+pub async fn execute(
+    plan: &RunPlan,
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> Result<RunReport, AppError> {
+    if let Some(report) = wait_for_target(plan, config, client, clock).await? {
+        return Ok(report);
+    }
+    switch_planned(plan, config, client).await
+}
+
+/// The waiting half of [`execute`]: applies any snooze and sleeps until the
+/// target. Returns the report of a run that ends here instead of switching,
+/// i.e. a snoozed day or a daemon's passed target.
+pub async fn wait_for_target(
+    plan: &RunPlan,
+    config: &Config,
+    client: Option<&Client>,
+    clock: &dyn Clock,
+) -> Result<Option<RunReport>, AppError> {
+    let event_time = plan.event_time;
+    let mut target_time = plan.target;
+    let job = plan.job.as_str();
+
+    // A snooze is spent on the next run that still has a wait ahead of it
+    if let (false, Some(_), Some(client)) =
+        (config.run_now, plan.sleep_duration(clock.now()), client)
+    {
+        match take_snooze(config, client, job).await? {
+            Some(Snooze::Skip) => {
+                return Ok(Some(skipped(config, plan, SkipReason::Snoozed)));
+            }
+            Some(Snooze::Shift(minutes)) => target_time += chrono::Duration::minutes(minutes),
+            None => {}
+        }
+    }
+
+    if let Some(day_length) = plan.day_length {
+        let message = format!(
+            "Day length today: {}h{:02}m.",
+            day_length.num_hours(),
+            day_length.num_minutes() % 60
+        );
+        config.console.info(&message);
+        log_event(&config.event_log, client, job, "Info", &message, "All").await?;
+    }
+
+    let remaining = target_time - clock.now();
+    let duration_to_sleep = (remaining > chrono::Duration::zero()).then_some(remaining);
+    if config.run_now {
+        let message = format!(
+            "Manual run: {} local is {}; switching now instead of at {}.",
+            plan.event.label(),
+            event_time.format("%Y-%m-%d %H:%M:%S"),
+            target_time.format("%Y-%m-%d %H:%M:%S")
+        );
+        config.console.info(&message);
+        log_event(&config.event_log, client, job, "Info", &message, "All").await?;
+    } else if let Some(duration_to_sleep) = duration_to_sleep.and_then(|d| d.to_std().ok()) {
+        let message = format!(
+            "{} local is {}. Sleeping for {} seconds until {} before {}.",
+            plan.event.label(),
+            event_time,
+            duration_to_sleep.as_secs(),
+            target_time,
+            plan.activity()
+        );
+        config.console.info(&message);
+        log_event(&config.event_log, client, job, "Info", &message, "All").await?;
+        config.events.publish(RunEvent::Scheduled {
+            job: job.to_string(),
+            target: target_time,
+        });
+        sleep(duration_to_sleep)
+            .instrument(tracing::info_span!(
+                "sleep",
+                seconds = duration_to_sleep.as_secs()
+            ))
+            .await;
+    } else if config.daemon {
+        let message = format!(
+            "Today's target {} has already passed. Waiting for tomorrow's {}.",
+            target_time.format("%Y-%m-%d %H:%M:%S"),
+            plan.event
+        );
+        log_skip(config, client, job, &message, "All").await?;
+        return Ok(Some(skipped(config, plan, SkipReason::TargetPassed)));
+    } else {
+        let message = format!(
+            "It is already close enough to {}. {} local today is {}. Switching lights immediately.",
+            plan.event,
+            plan.event.label(),
+            event_time.format("%Y-%m-%d %H:%M:%S")
+        );
+        config.console.info(&message);
+        log_event(&config.event_log, client, job, "Info", &message, "All").await?;
+    }
+    Ok(None)
+}
+
+/// The switching half of [`execute`], once the target is reached: sends
+/// every command unless the run is paused.
+pub async fn switch_planned(
+    plan: &RunPlan,
+    config: &Config,
+    client: Option<&Client>,
+) -> Result<RunReport, AppError> {
+    let job = plan.job.as_str();
+    // Checked after the wait so a pause set overnight still applies
+    if is_paused(config, client).await? {
+        log_skip(config, client, job, "Paused, skipping action.", "All").await?;
+        return Ok(skipped(config, plan, SkipReason::Paused));
+    }
+
+    switch(job, &plan.targets, config, client).await
+}
+
+/// The report of a run that sent nothing, published as completed.
+fn skipped(config: &Config, plan: &RunPlan, reason: SkipReason) -> RunReport {
+    let report = RunReport {
+        job: plan.job.clone(),
+        skipped: Some(reason),
+        ..RunReport::default()
+    };
+    config.events.publish(RunEvent::Completed {
+        report: report.clone(),
+    });
+    report
+}
+
+/// Sends every command in `targets` now, logs each outcome under `job` and
+/// records the results in `run_result`. Events logged meanwhile are inside a
+/// `run` span whose `run_id` is the run's `run_at`; with OTLP export the
+/// span also carries its OpenTelemetry `trace_id`.
+pub async fn switch(
+    job: &str,
+    targets: &Targets,
+    config: &Config,
+    client: Option<&Client>,
+) -> Result<RunReport, AppError> {
+    let run_at = Utc::now();
+    let span = tracing::info_span!(
+        "run",
+        run_id = %run_at.to_rfc3339(),
+        trace_id = tracing::field::Empty
+    );
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        let trace_id = span.context().span().span_context().trace_id();
+        span.record("trace_id", tracing::field::display(trace_id));
+    }
+    let local = LocalController::new(config.udp_bind())
+        .with_socks_proxy(config.udp_socks_proxy.clone())
+        .with_resolve_timeout(config.resolve_timeout);
+    let controller: Box<dyn LightController> = match &config.cloud_fallback {
+        Some(cloud) => Box::new(FallbackController::new(
+            local,
+            CloudController::new(
+                config.http.clone(),
+                cloud.base_url.clone(),
+                cloud.token.clone(),
+            ),
+        )),
+        None => Box::new(local),
+    };
+    switch_at(job, targets, controller.as_ref(), config, client, run_at)
+        .instrument(span)
+        .await
+}
+
+/// [`switch`], sending to lights through `controller`. The report is
+/// published as completed.
+async fn switch_at(
+    job: &str,
+    targets: &Targets,
+    controller: &dyn LightController,
+    config: &Config,
+    client: Option<&Client>,
+    run_at: DateTime<Utc>,
+) -> Result<RunReport, AppError> {
+    let mut report = RunReport {
+        job: job.to_string(),
+        run_at: Some(run_at),
+        ..RunReport::default()
+    };
+    match targets {
+        Targets::Broadcast { addr, command } => {
+            let started = Instant::now();
+            let result = match addr.parse() {
+                Ok(parsed) => {
+                    send_udp_broadcast(config.udp_bind(), parsed, &command.payload()).await
+                }
+                Err(e) => Err(AppError::from(e)),
+            };
+            let latency = started.elapsed();
+            match &result {
+                Ok(_) => {
+                    let message = format!(
+                        "Broadcast {} to {} sent; individual lights are not confirmed.",
+                        command, addr
+                    );
+                    config.console.success(&message);
+                    log_event(&config.event_log, client, job, "Info", &message, "All").await?;
+                }
+                Err(e) => {
+                    let message = format!("Failed to broadcast {} to {}: {}", command, addr, e);
+                    config.console.error(&message);
+                    log_event(&config.event_log, client, job, "Error", &message, "All").await?;
+                }
+            }
+            report.results.push(LightResult {
+                name: "All".to_string(),
+                host_id: addr.clone(),
+                room: None,
+                command: command.clone(),
+                outcome: match result {
+                    Ok(_) => Outcome::Switched,
+                    Err(e) => Outcome::Failed(e.to_string()),
+                },
+                latency,
+            });
+        }
+        // Switch each light according to its configured action
+        Targets::Lights(lights) => {
+            // The deadline covers sending only, not the wait for the target time
+            let deadline = config.run_deadline.map(|limit| Instant::now() + limit);
+            let mut sequenced =
+                send_sequences(controller, lights, config.settle_delay, deadline).await;
+            for (index, planned) in lights.iter().enumerate() {
+                let PlannedLight { light, command, .. } = planned;
+                let action = match &planned.sequence {
+                    Some(steps) => format!("{} after a {}-step sequence", command, steps.len()),
+                    None => command.to_string(),
+                };
+                if config.skip_if_off
+                    && !command.state
+                    && planned.sequence.is_none()
+                    && reports_off(config, light).await
+                {
+                    let message = format!(
+                        "Light {} at {} is already off, skipping.",
+                        light.name, light.host_id
+                    );
+                    log_skip(config, client, job, &message, &light.name).await?;
+                    report.results.push(LightResult {
+                        name: light.name.clone(),
+                        host_id: light.host_id.clone(),
+                        room: light.room.clone(),
+                        command: command.clone(),
+                        outcome: Outcome::AlreadyOff,
+                        latency: Duration::ZERO,
+                    });
+                    continue;
+                }
+                let (result, latency) = match sequenced[index].take() {
+                    Some(sent) => sent,
+                    None => {
+                        let payload = command.payload_for(light.firmware_profile);
+                        let send = controller
+                            .send(light, &payload)
+                            .instrument(light_span(light));
+                        let started = Instant::now();
+                        let result = match deadline {
+                            Some(deadline) if started >= deadline => None,
+                            Some(deadline) => timeout_at(deadline, send).await.ok(),
+                            None => Some(send.await),
+                        };
+                        (result, started.elapsed())
+                    }
+                };
+                let Some(result) = result else {
+                    let message = format!(
+                        "Light {} at {} timed out: run deadline of {}s reached before it could be turned {}",
+                        light.name,
+                        light.host_id,
+                        config.run_deadline.unwrap_or_default().as_secs(),
+                        action
+                    );
+                    config.console.error(&message);
+                    log_event(
+                        &config.event_log,
+                        client,
+                        job,
+                        "Error",
+                        &message,
+                        &light.name,
+                    )
+                    .await?;
+                    report.results.push(LightResult {
+                        name: light.name.clone(),
+                        host_id: light.host_id.clone(),
+                        room: light.room.clone(),
+                        command: command.clone(),
+                        outcome: Outcome::TimedOut,
+                        latency,
+                    });
+                    continue;
+                };
+                match &result {
+                    Ok(_) => {
+                        let severity: &str = "Info";
+                        let message: String = format!(
+                            "Light {} at {} turned {}! ({} ms)",
+                            light.name,
+                            light.host_id,
+                            action,
+                            latency.as_millis()
+                        );
+                        config.console.success(&message);
+                        log_event(
+                            &config.event_log,
+                            client,
+                            job,
+                            severity,
+                            &message,
+                            &light.name,
+                        )
+                        .await?;
+                        if config.verify_after {
+                            verify_state(config, client, job, light, command).await?;
+                        }
+                    }
+                    Err(e) if is_nonfatal(config, e) => {
+                        let message = format!(
+                            "Skipped light {} at {}: {} (listed in NONFATAL_IO_ERRORS)",
+                            light.name, light.host_id, e
+                        );
+                        config.console.warn(&message);
+                        log_event(
+                            &config.event_log,
+                            client,
+                            job,
+                            "Warn",
+                            &message,
+                            &light.name,
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        let severity: &str = "Error";
+                        let message = format!(
+                            "Failed to turn light {} at {} {} after {} ms: {}",
+                            light.name,
+                            light.host_id,
+                            action,
+                            latency.as_millis(),
+                            e
+                        );
+                        config.console.error(&message);
+                        log_event(
+                            &config.event_log,
+                            client,
+                            job,
+                            severity,
+                            &message,
+                            &light.name,
+                        )
+                        .await?;
+                        if let Some(safe_state) = &config.safe_state {
+                            apply_safe_state(config, client, job, controller, light, safe_state)
+                                .await?;
+                        }
+                    }
+                }
+                config.events.publish(match &result {
+                    Ok(_) => RunEvent::LightToggled {
+                        job: job.to_string(),
+                        light: light.name.clone(),
+                        command: command.clone(),
+                    },
+                    Err(e) if is_nonfatal(config, e) => RunEvent::Skipped {
+                        job: job.to_string(),
+                        machine: light.name.clone(),
+                        reason: e.to_string(),
+                    },
+                    Err(e) => RunEvent::LightFailed {
+                        job: job.to_string(),
+                        light: light.name.clone(),
+                        error: e.to_string(),
+                    },
+                });
+                report.results.push(LightResult {
+                    name: light.name.clone(),
+                    host_id: light.host_id.clone(),
+                    room: light.room.clone(),
+                    command: command.clone(),
+                    outcome: match result {
+                        Ok(_) => Outcome::Switched,
+                        Err(e) if is_nonfatal(config, &e) => Outcome::Skipped(e.to_string()),
+                        Err(e) => Outcome::Failed(e.to_string()),
+                    },
+                    latency,
+                });
+            }
+        }
+    }
+
+    // The lights are already switched, so bookkeeping must not fail the run
+    if let Some(client) = client {
+        if let Err(e) = store_run_results(client, run_at, &report).await {
+            config
+                .console
+                .warn(&format!("Could not record run results: {}", e));
+        }
+    }
+    config.events.publish(RunEvent::Completed {
+        report: report.clone(),
+    });
+    Ok(report)
+}
+
+/// Whether `NONFATAL_IO_ERRORS` classes a send error as a skip rather than
+/// a failure.
+fn is_nonfatal(config: &Config, error: &AppError) -> bool {
+    matches!(error, AppError::Io(e) if config.nonfatal_io_errors.contains(&e.kind()))
+}
+
+/// Sends `SAFE_STATE` to a light whose command failed. Tried once; a failure
+/// is only logged.
+async fn apply_safe_state(
+    config: &Config,
+    client: Option<&Client>,
+    job: &str,
+    controller: &dyn LightController,
+    light: &WizLight,
+    safe_state: &LightCommand,
+) -> Result<(), AppError> {
+    match controller
+        .send(light, &safe_state.payload_for(light.firmware_profile))
+        .await
+    {
+        Ok(()) => {
+            let message = format!(
+                "Reset light {} at {} to SAFE_STATE {}.",
+                light.name, light.host_id, safe_state
+            );
+            config.console.info(&message);
+            log_event(
+                &config.event_log,
+                client,
+                job,
+                "Info",
+                &message,
+                &light.name,
+            )
+            .await
+        }
+        Err(e) => {
+            let message = format!(
+                "Failed to reset light {} at {} to SAFE_STATE {}: {}",
+                light.name, light.host_id, safe_state, e
+            );
+            config.console.error(&message);
+            log_event(
+                &config.event_log,
+                client,
+                job,
+                "Error",
+                &message,
+                &light.name,
+            )
+            .await
+        }
+    }
+}
+
+/// A send's result, `None` when the run deadline passed first, and how long
+/// it took.
+type Sent = (Option<Result<(), AppError>>, Duration);
+
+/// A light partway through its sequence in [`send_sequences`].
+struct SequenceRun<'a> {
+    index: usize,
+    planned: &'a PlannedLight,
+    steps: &'a [SequenceStep],
+    step: usize,
+    /// When the step is scheduled, timed from the start so send latency
+    /// doesn't accumulate.
+    next_step: Instant,
+    /// When the step is sent: `next_step`, or later while an acknowledging
+    /// light settles.
+    due: Instant,
+}
+
+/// Runs the sequences of all `lights` that have one together: whenever
+/// steps fall due they are sent, one light after another, so every light
+/// keeps to its own schedule. Run one light at a time, the last light of a
+/// ramp would only start once the others had finished. A light that
+/// acknowledges commands gets at least `settle_delay` after each ack before
+/// its next step, and a failed step ends its sequence. Returns what was
+/// sent per position in `lights`; `None` for lights without a sequence.
+async fn send_sequences(
+    controller: &dyn LightController,
+    lights: &[PlannedLight],
+    settle_delay: Duration,
+    deadline: Option<Instant>,
+) -> Vec<Option<Sent>> {
+    let started = Instant::now();
+    let mut sent: Vec<Option<Sent>> = lights.iter().map(|_| None).collect();
+    let mut runs: Vec<SequenceRun> = lights
+        .iter()
+        .enumerate()
+        .filter_map(|(index, planned)| {
+            let steps = planned
+                .sequence
+                .as_deref()
+                .filter(|steps| !steps.is_empty())?;
+            Some(SequenceRun {
+                index,
+                planned,
+                steps,
+                step: 0,
+                next_step: started,
+                due: started,
+            })
+        })
+        .collect();
+
+    while let Some(due) = runs.iter().map(|run| run.due).min() {
+        if deadline.is_some_and(|deadline| due >= deadline) {
+            break;
+        }
+        sleep_until(due).await;
+        let mut waiting = Vec::with_capacity(runs.len());
+        for mut run in runs {
+            if run.due > due {
+                waiting.push(run);
+                continue;
+            }
+            let light = &run.planned.light;
+            let step = &run.steps[run.step];
+            let payload = step.command.payload_for(light.firmware_profile);
+            let send = controller
+                .send(light, &payload)
+                .instrument(light_span(light));
+            let result = match deadline {
+                Some(deadline) => timeout_at(deadline, send).await.ok(),
+                None => Some(send.await),
+            };
+            match result {
+                Some(Ok(())) if run.step + 1 < run.steps.len() => {
+                    run.next_step += step.delay;
+                    run.due = match light.require_ack {
+                        true => run.next_step.max(Instant::now() + settle_delay),
+                        false => run.next_step,
+                    };
+                    run.step += 1;
+                    waiting.push(run);
+                }
+                result => sent[run.index] = Some((result, started.elapsed())),
+            }
+        }
+        runs = waiting;
+    }
+    // Sequences still running when the deadline passed
+    for run in runs {
+        sent[run.index] = Some((None, started.elapsed()));
+    }
+    sent
+}
+
+/// The span each send to `light` runs in.
+fn light_span(light: &WizLight) -> tracing::Span {
+    tracing::info_span!("light", name = %light.name, host = %light.host_id)
+}
+
+/// Re-reads a light's state `CONFIRM_DELAY_MS` after switching it and warns
+/// when the bulb acknowledged the command but didn't act on it.
+async fn verify_state(
+    config: &Config,
+    client: Option<&Client>,
+    job: &str,
+    light: &WizLight,
+    command: &LightCommand,
+) -> Result<(), AppError> {
+    sleep(config.confirm_delay).await;
+    let state = match resolve(&light.host_id, config.resolve_timeout).await {
+        Ok(addr) => query_udp(
+            config.udp_bind(),
+            addr,
+            GET_PILOT_REQUEST,
+            PROBE_REPLY_TIMEOUT,
+        )
+        .await
+        .and_then(|reply| pilot_state(&reply)),
+        Err(e) => Err(e),
+    };
+    let message = match state {
+        Ok(state) if state == command.state => return Ok(()),
+        Ok(state) => format!(
+            "Light {} at {} acknowledged {} but reports it is {}.",
+            light.name,
+            light.host_id,
+            command,
+            if state { "on" } else { "off" }
+        ),
+        Err(e) => format!(
+            "Could not verify light {} at {}: {}",
+            light.name, light.host_id, e
+        ),
+    };
+    config.console.warn(&message);
+    log_event(
+        &config.event_log,
+        client,
+        job,
+        "Warn",
+        &message,
+        &light.name,
+    )
+    .await
+}
+
+/// Whether a light answers `getPilot` with its state off. A light that
+/// doesn't answer is assumed on, so it is still sent the command.
+async fn reports_off(config: &Config, light: &WizLight) -> bool {
+    let Ok(addr) = resolve(&light.host_id, config.resolve_timeout).await else {
+        return false;
+    };
+    query_udp(
+        config.udp_bind(),
+        addr,
+        GET_PILOT_REQUEST,
+        PROBE_REPLY_TIMEOUT,
+    )
+    .await
+    .and_then(|reply| pilot_state(&reply))
+    .is_ok_and(|on| !on)
+}
+
+/// How long each startup probe waits for a bulb to answer.
+const PROBE_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Pause between rounds of startup probes.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits until any light answers `getPilot`, so a run right after a power
+/// cut doesn't fire before the bulbs have rejoined WiFi. Gives up after
+/// `WAIT_FOR_LIGHTS_TIMEOUT`; returns whether a light answered. The lights
+/// are loaded, and discovered, once; each round only probes them.
+pub async fn wait_for_lights(config: &Config, client: Option<&Client>) -> Result<bool, AppError> {
+    let deadline = Instant::now() + config.wait_for_lights_timeout;
+    let lights = load_lights(config, client, STARTUP_JOB).await?;
+    let mut round = 1;
+    loop {
+        for light in &lights {
+            let reply = match resolve(&light.host_id, config.resolve_timeout).await {
+                Ok(addr) => {
+                    query_udp(
+                        config.udp_bind(),
+                        addr,
+                        GET_PILOT_REQUEST,
+                        PROBE_REPLY_TIMEOUT,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if reply.is_ok() {
+                let message = format!(
+                    "Light {} at {} is reachable; starting.",
+                    light.name, light.host_id
+                );
+                config.console.info(&message);
+                log_event(
+                    &config.event_log,
+                    client,
+                    STARTUP_JOB,
+                    "Info",
+                    &message,
+                    &light.name,
+                )
+                .await?;
+                return Ok(true);
+            }
+        }
+
+        if Instant::now() + PROBE_INTERVAL >= deadline {
+            let message = format!(
+                "No light answered within {}s (WAIT_FOR_LIGHTS_TIMEOUT); starting anyway.",
+                config.wait_for_lights_timeout.as_secs()
+            );
+            config.console.warn(&message);
+            log_event(
+                &config.event_log,
+                client,
+                STARTUP_JOB,
+                "Warn",
+                &message,
+                "All",
+            )
+            .await?;
+            return Ok(false);
+        }
+        let message = format!(
+            "Waiting for lights to come online (attempt {}, {} lights probed).",
+            round,
+            lights.len()
+        );
+        config.console.info(&message);
+        log_event(
+            &config.event_log,
+            client,
+            STARTUP_JOB,
+            "Info",
+            &message,
+            "All",
+        )
+        .await?;
+        sleep(PROBE_INTERVAL).await;
+        round += 1;
+    }
+}
+
+/// Sends `getPilot` to every light each `interval`, so bulbs that sleep
+/// deeply stay responsive for the scheduled commands. Lights are reloaded
+/// once a day. Runs until the daemon exits; failures only reach debug logs.
+pub async fn keepalive(config: &Config, client: Option<&Client>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut lights = Vec::new();
+    let mut loaded_on = None;
+    loop {
+        ticker.tick().await;
+        let today = Local::now().date_naive();
+        if loaded_on != Some(today) {
+            match source_lights(config, client).await {
+                Ok(loaded) => {
+                    lights = filter_lights(
+                        loaded,
+                        config.include_lights.as_deref(),
+                        &config.exclude_lights,
+                    )
+                    .0;
+                    loaded_on = Some(today);
+                }
+                Err(e) => tracing::debug!(error = %e, "keepalive could not load lights"),
+            }
+        }
+        for light in &lights {
+            let reply = match resolve(&light.host_id, config.resolve_timeout).await {
+                Ok(addr) => {
+                    query_udp(
+                        config.udp_bind(),
+                        addr,
+                        GET_PILOT_REQUEST,
+                        PROBE_REPLY_TIMEOUT,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match reply {
+                Ok(_) => tracing::debug!(light = %light.name, "keepalive answered"),
+                Err(e) => tracing::debug!(light = %light.name, error = %e, "keepalive failed"),
+            }
+        }
+    }
+}
+
+/// Reports an action deliberately not taken. The console always shows it;
+/// the `log` table only when `LOG_SKIPS` is on and not previewing.
+async fn log_skip(
+    config: &Config,
+    client: Option<&Client>,
+    job: &str,
+    message: &str,
+    machine: &str,
+) -> Result<(), AppError> {
+    config.console.info(message);
+    config.events.publish(RunEvent::Skipped {
+        job: job.to_string(),
+        machine: machine.to_string(),
+        reason: message.to_string(),
+    });
+    if !config.log_skips || config.preview {
+        return Ok(());
+    }
+    log_event(&config.event_log, client, job, "Info", message, machine).await
+}
+
+/// A one-off change to the next run, set through the `snooze` control row.
+enum Snooze {
+    Skip,
+    /// Minutes to move the target by; negative values bring it forward.
+    Shift(i64),
+}
+
+/// Reads and clears the `snooze` control row: `skip` (or `true`) skips the
+/// next run once, a number of minutes moves its target.
+async fn take_snooze(
+    config: &Config,
+    client: &Client,
+    job: &str,
+) -> Result<Option<Snooze>, AppError> {
+    let Some(value) = take_control(client, "snooze").await? else {
+        return Ok(None);
+    };
+    let (snooze, message) = match value.trim().parse::<i64>() {
+        Ok(minutes) => (
+            Some(Snooze::Shift(minutes)),
+            format!("Snooze consumed: moving this run by {} minutes.", minutes),
+        ),
+        Err(_) if value.trim().eq_ignore_ascii_case("skip") || parse_flag(&value) == Some(true) => {
+            (
+                Some(Snooze::Skip),
+                "Snooze consumed: skipping this run.".to_string(),
+            )
+        }
+        Err(_) => {
+            let message = format!(
+                "Ignoring snooze value {:?}; expected \"skip\" or a number of minutes.",
+                value
+            );
+            config.console.warn(&message);
+            log_event(
+                &config.event_log,
+                Some(client),
+                job,
+                "Warn",
+                &message,
+                "All",
+            )
+            .await?;
+            return Ok(None);
+        }
+    };
+    config.console.info(&message);
+    log_event(
+        &config.event_log,
+        Some(client),
+        job,
+        "Info",
+        &message,
+        "All",
+    )
+    .await?;
+    Ok(snooze)
+}
+
+/// True when `PAUSED` is set or the `control` table's `paused` row is true.
+async fn is_paused(config: &Config, client: Option<&Client>) -> Result<bool, AppError> {
+    if config.paused {
+        return Ok(true);
+    }
+    let Some(client) = client else {
+        return Ok(false);
+    };
+    Ok(load_control(client, "paused")
+        .await?
+        .and_then(|value| parse_flag(&value))
+        .unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeClock;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+    use std::sync::Mutex;
+
+    /// Records each send, which takes `latency`, as the light's name, the
+    /// payload and when the send completed.
+    struct RecordingController {
+        latency: Duration,
+        sent: Mutex<Vec<(String, String, Instant)>>,
+    }
+
+    #[async_trait]
+    impl LightController for RecordingController {
+        async fn send(&self, light: &WizLight, payload: &str) -> Result<(), AppError> {
+            sleep(self.latency).await;
+            let sent = (light.name.clone(), payload.to_string(), Instant::now());
+            self.sent.lock().unwrap().push(sent);
+            Ok(())
+        }
+    }
+
+    fn morning_plan(target: DateTime<Local>) -> RunPlan {
+        RunPlan {
+            job: MORNING_JOB.to_string(),
+            event: SolarEvent::Sunrise,
+            event_time: target - chrono::Duration::minutes(30),
+            target,
+            day_length: None,
+            targets: Targets::Lights(Vec::new()),
+        }
+    }
+
+    fn seven_am() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 10, 15, 7, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_fail_thresholds() {
+        let cases = [
+            ("0", FailThreshold::Count(0)),
+            (" 3 ", FailThreshold::Count(3)),
+            ("25%", FailThreshold::Percent(25.0)),
+            ("12.5 %", FailThreshold::Percent(12.5)),
+            ("100%", FailThreshold::Percent(100.0)),
+        ];
+        for (input, threshold) in cases {
+            let parsed: FailThreshold = input.parse().unwrap();
+            assert_eq!(parsed, threshold, "{}", input);
+            assert_eq!(parsed.to_string().parse::<FailThreshold>().unwrap(), parsed);
+        }
+        for input in ["", "-1", "101%", "a%", "two"] {
+            assert!(input.parse::<FailThreshold>().is_err(), "{}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn daemon_skips_a_passed_target() {
+        let mut config = Config::for_tests().await;
+        config.daemon = true;
+        let clock = FakeClock::new(seven_am());
+        let plan = morning_plan(seven_am() + chrono::Duration::minutes(5));
+        clock.advance(chrono::Duration::minutes(6));
+
+        let report = wait_for_target(&plan, &config, None, &clock)
+            .await
+            .unwrap()
+            .expect("a skipped run");
+        assert!(matches!(report.skipped, Some(SkipReason::TargetPassed)));
+        assert!(report.results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn one_shot_switches_at_a_passed_target() {
+        let config = Config::for_tests().await;
+        let clock = FakeClock::new(seven_am());
+        clock.set(seven_am() + chrono::Duration::hours(1));
+
+        let report = wait_for_target(&morning_plan(seven_am()), &config, None, &clock)
+            .await
+            .unwrap();
+        assert!(report.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleeps_until_a_future_target() {
+        let config = Config::for_tests().await;
+        let clock = FakeClock::new(seven_am());
+        let plan = morning_plan(seven_am() + chrono::Duration::milliseconds(90_250));
+
+        let started = Instant::now();
+        let report = wait_for_target(&plan, &config, None, &clock).await.unwrap();
+        assert!(report.is_none());
+        assert_eq!(started.elapsed(), Duration::from_millis(90_250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_light_finishes_its_ramp_on_time() {
+        let ramp_length = Duration::from_secs(20 * 60);
+        let command: LightCommand = "on,dim:100".parse().unwrap();
+        let ramp = ramp_sequence(&command, ramp_length);
+        let lights: Vec<PlannedLight> = ["192.168.1.10", "192.168.1.11", "192.168.1.12"]
+            .into_iter()
+            .map(|addr| PlannedLight {
+                light: WizLight::from_addr(addr, "192.168.1"),
+                command: command.clone(),
+                sequence: Some(ramp.clone()),
+            })
+            .collect();
+        let controller = RecordingController {
+            latency: Duration::from_secs(1),
+            sent: Mutex::new(Vec::new()),
+        };
+
+        let started = Instant::now();
+        let sent = send_sequences(&controller, &lights, Duration::ZERO, None).await;
+        assert!(sent
+            .iter()
+            .all(|sent| matches!(sent, Some((Some(Ok(())), _)))));
+
+        let sends = controller.sent.into_inner().unwrap();
+        let target = started + ramp_length + Duration::from_secs(lights.len() as u64);
+        let final_payload = ramp[ramp.len() - 1].command.payload();
+        for planned in &lights {
+            let (_, payload, finished) = sends
+                .iter()
+                .rfind(|(name, _, _)| *name == planned.light.name)
+                .unwrap();
+            assert_eq!(*payload, final_payload);
+            assert!(*finished <= target, "{} finished late", planned.light.name);
+        }
+        assert_eq!(sends.len(), lights.len() * ramp.len());
+    }
+
+    #[tokio::test]
+    async fn publishes_planned_sent_and_completed_in_order() {
+        let mut config = Config::for_tests().await;
+        config.lights = vec!["10".to_string(), "11".to_string()];
+        let job = Job {
+            name: "Lamps".to_string(),
+            event: SolarEvent::Clock(chrono::NaiveTime::from_hms_opt(7, 0, 0).unwrap()),
+            offset_minutes: 0,
+            command: Some(LightCommand::on()),
+            ramp_until: None,
+        };
+        let controller = RecordingController {
+            latency: Duration::ZERO,
+            sent: Mutex::new(Vec::new()),
+        };
+        let mut events = config.events.subscribe();
+
+        let plan = plan_job(&config, None, &FakeClock::new(seven_am()), &job)
+            .await
+            .unwrap();
+        let report = switch_at(
+            &plan.job,
+            &plan.targets,
+            &controller,
+            &config,
+            None,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            RunEvent::Planned { job, lights: 2, .. } if job == "Lamps"
+        ));
+        for light in ["10", "11"] {
+            assert!(matches!(
+                events.try_recv().unwrap(),
+                RunEvent::LightToggled { light: toggled, .. } if toggled == light
+            ));
+        }
+        assert_eq!(events.try_recv().unwrap(), RunEvent::Completed { report });
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::AppError;
+use crate::light::LightCommand;
+use crate::sunrise::SolarTimes;
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+/// Name of the built-in job, also used as its log `event_type`.
+pub const MORNING_JOB: &str = "Morning";
+
+/// Log `event_type` of runs started with `--run-now`.
+pub const MANUAL_JOB: &str = "Manual";
+
+/// Log `event_type` of the startup gate (`WAIT_FOR_LIGHTS`).
+pub const STARTUP_JOB: &str = "Startup";
+
+/// How long before sunrise the built-in job switches the lights.
+const MORNING_OFFSET_MINUTES: i64 = -30;
+
+/// The point in the day a job is anchored to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SolarEvent {
+    Sunrise,
+    Sunset,
+    CivilTwilightBegin,
+    SolarNoon,
+    /// A fixed local time of day, e.g. `06:00`, regardless of the sun.
+    Clock(NaiveTime),
+}
+
+impl SolarEvent {
+    /// When the event happens on `day`. `None` for a solar event without
+    /// the day's `times`, which a clock time doesn't need.
+    pub fn time(self, day: NaiveDate, times: Option<&SolarTimes>) -> Option<DateTime<Utc>> {
+        let time = match (self, times) {
+            (SolarEvent::Clock(time), _) => return Some(local_clock_time(day, time)),
+            (_, None) => return None,
+            (SolarEvent::Sunrise, Some(times)) => times.sunrise,
+            (SolarEvent::Sunset, Some(times)) => times.sunset,
+            (SolarEvent::CivilTwilightBegin, Some(times)) => times.civil_twilight_begin,
+            (SolarEvent::SolarNoon, Some(times)) => times.solar_noon,
+        };
+        Some(time)
+    }
+
+    /// Whether the event depends on the day's solar times.
+    pub fn is_solar(self) -> bool {
+        !matches!(self, SolarEvent::Clock(_))
+    }
+
+    /// Capitalized name for the start of a sentence.
+    pub fn label(self) -> &'static str {
+        match self {
+            SolarEvent::Sunrise => "Sunrise",
+            SolarEvent::Sunset => "Sunset",
+            SolarEvent::CivilTwilightBegin => "Civil twilight",
+            SolarEvent::SolarNoon => "Solar noon",
+            SolarEvent::Clock(_) => "Scheduled time",
+        }
+    }
+}
+
+/// `time` on `day` in local time. A time skipped by a DST change moves an
+/// hour later, and one that occurs twice is taken the first time.
+fn local_clock_time(day: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+    let naive = day.and_time(time);
+    naive
+        .and_local_timezone(Local)
+        .earliest()
+        .or_else(|| {
+            (naive + chrono::Duration::hours(1))
+                .and_local_timezone(Local)
+                .earliest()
+        })
+        .map_or_else(|| naive.and_utc(), |time| time.with_timezone(&Utc))
+}
+
+impl fmt::Display for SolarEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SolarEvent::Sunrise => "sunrise",
+            SolarEvent::Sunset => "sunset",
+            SolarEvent::CivilTwilightBegin => "civil twilight",
+            SolarEvent::SolarNoon => "solar noon",
+            SolarEvent::Clock(time) => return write!(f, "{}", time.format("%H:%M")),
+        })
+    }
+}
+
+impl FromStr for SolarEvent {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sunrise" => Ok(SolarEvent::Sunrise),
+            "sunset" => Ok(SolarEvent::Sunset),
+            "civil_twilight_begin" => Ok(SolarEvent::CivilTwilightBegin),
+            "solar_noon" => Ok(SolarEvent::SolarNoon),
+            clock if clock.contains(':') => parse_clock_time(clock)
+                .map(SolarEvent::Clock)
+                .ok_or_else(|| {
+                    AppError::InvalidConfig(format!("clock time {:?} must be HH:MM", s))
+                }),
+            _ => Err(AppError::InvalidConfig(format!(
+                "unknown solar event {:?}, expected sunrise, sunset, civil_twilight_begin, solar_noon or an HH:MM clock time",
+                s
+            ))),
+        }
+    }
+}
+
+/// Parses a strict 24-hour `HH:MM`, such as `06:00`.
+fn parse_clock_time(s: &str) -> Option<NaiveTime> {
+    let (hours, minutes) = s.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    NaiveTime::from_hms_opt(hours.parse().ok()?, minutes.parse().ok()?, 0)
+}
+
+/// A named action at a fixed offset from a solar event or clock time.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub name: String,
+    pub event: SolarEvent,
+    /// Minutes after the event; negative values fire before it.
+    pub offset_minutes: i64,
+    /// Sent to every light. `None` uses each light's own action and
+    /// `MORNING_COMMAND`, as the built-in morning routine does.
+    pub command: Option<LightCommand>,
+    /// Fade the command's brightness in from the target time until this
+    /// event, e.g. from civil twilight to sunrise.
+    pub ramp_until: Option<SolarEvent>,
+}
+
+impl Job {
+    /// The routine run when no schedule is configured: 30 minutes before
+    /// sunrise, each light gets its configured action.
+    pub fn morning() -> Self {
+        Job {
+            name: MORNING_JOB.to_string(),
+            event: SolarEvent::Sunrise,
+            offset_minutes: MORNING_OFFSET_MINUTES,
+            command: None,
+            ramp_until: None,
+        }
+    }
+}
+
+/// Loads the enabled rows of the `schedule` table; `solar_event` may hold
+/// an `HH:MM` clock time instead of an event. A missing table is the
+/// same as an empty schedule. Rows that fail to parse are returned as errors
+/// so the remaining jobs can still run.
+pub async fn load_schedule(client: &Client) -> Result<Vec<Result<Job, AppError>>, AppError> {
+    let rows = match client
+        .query(
+            "SELECT name, solar_event, offset_minutes, command, ramp_until FROM schedule \
+             WHERE enabled ORDER BY name",
+            &[],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let name: String = row.get("name");
+            let event: String = row.get("solar_event");
+            let offset_minutes: i32 = row.get("offset_minutes");
+            let command: String = row.get("command");
+            let ramp_until: Option<String> = row.get("ramp_until");
+            let invalid = |e: AppError| AppError::InvalidJob {
+                name: name.clone(),
+                reason: e.to_string(),
+            };
+            Ok(Job {
+                event: event.parse().map_err(invalid)?,
+                offset_minutes: offset_minutes.into(),
+                command: Some(command.parse().map_err(invalid)?),
+                ramp_until: ramp_until
+                    .map(|event| event.parse())
+                    .transpose()
+                    .map_err(invalid)?,
+                name,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,109 @@
+use crate::error::AppError;
+use crate::light::{LightCommand, WizLight};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+/// A `seasonal_command` row: the command the morning routine sends during
+/// the months `start_month..=end_month` (which may wrap past December), to
+/// one light, to a room, or to every light when both are empty.
+#[derive(Clone, Debug)]
+pub struct SeasonalRule {
+    pub start_month: u32,
+    pub end_month: u32,
+    pub light: Option<String>,
+    pub room: Option<String>,
+    pub command: LightCommand,
+}
+
+impl SeasonalRule {
+    fn in_season(&self, month: u32) -> bool {
+        if self.start_month <= self.end_month {
+            (self.start_month..=self.end_month).contains(&month)
+        } else {
+            month >= self.start_month || month <= self.end_month
+        }
+    }
+
+    /// 2 for a rule naming the light, 1 for its room, 0 for every light, or
+    /// `None` when the rule doesn't cover it.
+    fn specificity(&self, light: &WizLight) -> Option<u8> {
+        let matches = |wanted: &Option<String>, actual: Option<&str>| match wanted {
+            Some(wanted) => actual.is_some_and(|actual| actual.eq_ignore_ascii_case(wanted)),
+            None => true,
+        };
+        if !matches(&self.light, Some(&light.name)) || !matches(&self.room, light.room.as_deref()) {
+            return None;
+        }
+        Some(match (&self.light, &self.room) {
+            (Some(_), _) => 2,
+            (None, Some(_)) => 1,
+            (None, None) => 0,
+        })
+    }
+}
+
+/// Loads the `seasonal_command` rules. Without the table there are none.
+pub async fn load_seasonal_rules(client: &Client) -> Result<Vec<SeasonalRule>, AppError> {
+    let rows = match client
+        .query(
+            "SELECT start_month, end_month, light, room, command FROM seasonal_command",
+            &[],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    rows.iter()
+        .map(|row| {
+            let start_month: i32 = row.get("start_month");
+            let end_month: i32 = row.get("end_month");
+            let command: String = row.get("command");
+            let invalid = |reason: String| {
+                AppError::InvalidConfig(format!(
+                    "seasonal_command for months {}-{}: {}",
+                    start_month, end_month, reason
+                ))
+            };
+            if ![start_month, end_month]
+                .iter()
+                .all(|month| (1..=12).contains(month))
+            {
+                return Err(invalid("months must be between 1 and 12".to_string()));
+            }
+            Ok(SeasonalRule {
+                start_month: start_month as u32,
+                end_month: end_month as u32,
+                light: row.get("light"),
+                room: row.get("room"),
+                command: command
+                    .parse()
+                    .map_err(|e: AppError| invalid(e.to_string()))?,
+            })
+        })
+        .collect()
+}
+
+/// The command the most specific in-season rule gives `light` in `month`:
+/// a rule naming the light beats one for its room, which beats one for
+/// every light. Among equally specific rules the first listed wins.
+pub fn seasonal_command<'a>(
+    rules: &'a [SeasonalRule],
+    month: u32,
+    light: &WizLight,
+) -> Option<&'a LightCommand> {
+    rules
+        .iter()
+        .filter(|rule| rule.in_season(month))
+        .filter_map(|rule| Some((rule.specificity(light)?, rule)))
+        .fold(
+            None,
+            |best: Option<(u8, &SeasonalRule)>, (rank, rule)| match best {
+                Some((best_rank, _)) if best_rank >= rank => best,
+                _ => Some((rank, rule)),
+            },
+        )
+        .map(|(_, rule)| &rule.command)
+}
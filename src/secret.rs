@@ -0,0 +1,45 @@
+use crate::error::AppError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+
+/// Bytes of the random nonce stored in front of the ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// Decrypts `DB_PASSWORD_ENC`: base64 of a 12-byte nonce followed by the
+/// ChaCha20-Poly1305 ciphertext. The key file holds the 32-byte key, raw or
+/// base64-encoded.
+pub fn decrypt_password(encrypted: &str, key_path: &str) -> Result<String, AppError> {
+    let invalid = |reason: String| AppError::InvalidConfig(format!("DB_PASSWORD_ENC: {}", reason));
+    let key_bytes = std::fs::read(key_path)
+        .map_err(|e| invalid(format!("cannot read DB_KEY_FILE {}: {}", key_path, e)))?;
+    let key_bytes = match key_bytes.len() {
+        32 => key_bytes,
+        _ => STANDARD
+            .decode(String::from_utf8_lossy(&key_bytes).trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "DB_KEY_FILE {} must hold a 32-byte key, raw or base64",
+                    key_path
+                ))
+            })?,
+    };
+
+    let data = STANDARD
+        .decode(encrypted.trim())
+        .map_err(|e| invalid(format!("not valid base64: {}", e)))?;
+    if data.len() <= NONCE_LEN {
+        return Err(invalid(
+            "too short to hold a nonce and ciphertext".to_string(),
+        ));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key_bytes));
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| invalid("decryption failed; wrong key or corrupted value".to_string()))?;
+    String::from_utf8(plaintext).map_err(|_| invalid("decrypted password is not UTF-8".to_string()))
+}
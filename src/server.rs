@@ -0,0 +1,194 @@
+use crate::clock::SystemClock;
+use crate::config::Config;
+use crate::db::log_event;
+use crate::error::AppError;
+use crate::light::LightCommand;
+use crate::plan::{
+    load_lights, plan_run, switch, switch_planned, PlannedLight, RunPlan, RunReport, Targets,
+};
+use crate::schedule::MANUAL_JOB;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_postgres::Client;
+
+/// Header carrying `WEBHOOK_SECRET` on inbound requests.
+const SECRET_HEADER: &str = "x-webhook-secret";
+
+/// Longest request head read before the request is rejected.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Pause after a failed accept, so a persistent error such as EMFILE
+/// doesn't spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the inbound webhook on `port`: `POST /run` switches the morning
+/// lights now, `POST /turn-off` and `POST /turn-on` switch every light.
+/// Each replies with the run report as JSON. Requests are handled one at a
+/// time and must carry `secret` in the `X-Webhook-Secret` header.
+pub async fn serve_webhook(
+    config: &Config,
+    client: Option<&Client>,
+    port: u16,
+    secret: &str,
+) -> Result<(), AppError> {
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    tracing::info!(port, "inbound webhook listening");
+    loop {
+        // Errors such as EMFILE or ECONNABORTED are transient and must not
+        // stop the daemon
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!(error = %e, "inbound webhook accept failed");
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = handle(config, client, stream, secret).await {
+            tracing::warn!(%peer, error = %e, "inbound webhook request failed");
+        }
+    }
+}
+
+async fn handle(
+    config: &Config,
+    client: Option<&Client>,
+    mut stream: TcpStream,
+    secret: &str,
+) -> Result<(), AppError> {
+    let head = match timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => {
+            return respond(
+                &mut stream,
+                408,
+                "Request Timeout",
+                &error_body("timed out"),
+            )
+            .await
+        }
+    };
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+    let authorized = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case(SECRET_HEADER)
+                && constant_time_eq(value.trim().as_bytes(), secret.as_bytes())
+        })
+    });
+    if !authorized {
+        return respond(&mut stream, 401, "Unauthorized", &error_body("bad secret")).await;
+    }
+
+    let command = match (method, path) {
+        (Some("POST"), Some("/run")) => None,
+        (Some("POST"), Some("/turn-off")) => Some(LightCommand::off()),
+        (Some("POST"), Some("/turn-on")) => Some(LightCommand::on()),
+        _ => return respond(&mut stream, 404, "Not Found", &error_body("not found")).await,
+    };
+    let message = format!(
+        "Inbound webhook {} triggered a run.",
+        path.unwrap_or_default()
+    );
+    config.console.info(&message);
+    log_event(
+        &config.event_log,
+        client,
+        MANUAL_JOB,
+        "Info",
+        &message,
+        "All",
+    )
+    .await?;
+
+    match trigger(config, client, command).await {
+        Ok(report) => {
+            let body = serde_json::to_string(&report).expect("run reports serialize to JSON");
+            respond(&mut stream, 200, "OK", &body).await
+        }
+        Err(e) => {
+            let body = error_body(&e.to_string());
+            respond(&mut stream, 500, "Internal Server Error", &body).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Sends `command` to every light, or the planned morning commands when
+/// `None`, right away. A planned run honours a pause like a scheduled one
+/// and reports itself skipped.
+async fn trigger(
+    config: &Config,
+    client: Option<&Client>,
+    command: Option<LightCommand>,
+) -> Result<RunReport, AppError> {
+    let targets = match command {
+        None => {
+            let plan = RunPlan {
+                job: MANUAL_JOB.to_string(),
+                ..plan_run(config, client, &SystemClock).await?
+            };
+            return switch_planned(&plan, config, client).await;
+        }
+        Some(command) => Targets::Lights(
+            load_lights(config, client, MANUAL_JOB)
+                .await?
+                .into_iter()
+                .map(|light| PlannedLight {
+                    light,
+                    command: command.clone(),
+                    sequence: None,
+                })
+                .collect(),
+        ),
+    };
+    switch(MANUAL_JOB, &targets, config, client).await
+}
+
+/// Reads up to the blank line ending the request head; any body is ignored.
+async fn read_head(stream: &mut TcpStream) -> Result<String, AppError> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || head.len() + read > MAX_REQUEST_LEN {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    reason: &str,
+    body: &str,
+) -> Result<(), AppError> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// Compares secrets without stopping at the first mismatch, so response
+/// timing doesn't reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,141 @@
+use crate::error::AppError;
+use crate::transport::{bind_udp, UdpBind};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// How long connecting to the proxy and negotiating the relay may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A UDP relay granted by a SOCKS5 proxy through UDP ASSOCIATE (RFC 1928),
+/// for bulbs on a LAN only reachable through the proxy. The proxy must allow
+/// UDP ASSOCIATE without authentication; many SOCKS servers, `ssh -D`
+/// included, only relay TCP and refuse it. The relay lasts as long as the
+/// control connection, so that is kept open alongside the socket.
+pub struct SocksUdp {
+    _control: TcpStream,
+    socket: UdpSocket,
+}
+
+impl SocksUdp {
+    /// Asks `proxy` for a UDP relay, sending from a socket bound to
+    /// `bind`. Fails when the proxy hasn't granted one within
+    /// [`HANDSHAKE_TIMEOUT`].
+    pub async fn associate(proxy: &str, bind: UdpBind) -> Result<Self, AppError> {
+        let (control, relay) = timeout(HANDSHAKE_TIMEOUT, handshake(proxy))
+            .await
+            .map_err(|_| AppError::NoReply(proxy.to_string(), HANDSHAKE_TIMEOUT))??;
+        let socket = bind_udp(bind).await?;
+        socket.connect(relay).await?;
+        Ok(SocksUdp {
+            _control: control,
+            socket,
+        })
+    }
+
+    /// Sends `payload` to `addr` through the relay. Returns the bytes of
+    /// payload sent, excluding the SOCKS header.
+    pub async fn send_to(&self, payload: &[u8], addr: SocketAddr) -> Result<usize, AppError> {
+        let mut datagram = vec![0, 0, 0];
+        push_addr(&mut datagram, addr);
+        let header = datagram.len();
+        datagram.extend_from_slice(payload);
+        let sent = self.socket.send(&datagram).await?;
+        Ok(sent.saturating_sub(header))
+    }
+
+    /// Receives one relayed datagram into `buf`, returning its payload length
+    /// once the SOCKS header is stripped.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize, AppError> {
+        let mut datagram = vec![0u8; buf.len() + 22];
+        let read = self.socket.recv(&mut datagram).await?;
+        let header = match datagram.get(3) {
+            Some(&ATYP_IPV4) => 10,
+            Some(&ATYP_IPV6) => 22,
+            _ => {
+                return Err(AppError::InvalidReply(
+                    "malformed SOCKS UDP datagram".to_string(),
+                ))
+            }
+        };
+        // A relayed datagram larger than `buf` is truncated, as a UDP socket
+        // would truncate it
+        let payload = datagram.get(header..read).unwrap_or_default();
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+        Ok(len)
+    }
+}
+
+/// Opens the control connection to `proxy` and negotiates UDP ASSOCIATE,
+/// returning the connection and the relay address granted.
+async fn handshake(proxy: &str) -> Result<(TcpStream, SocketAddr), AppError> {
+    let invalid =
+        |reason: &str| AppError::InvalidReply(format!("SOCKS proxy {}: {}", proxy, reason));
+    let mut control = TcpStream::connect(proxy).await?;
+    control.write_all(&[VERSION, 1, NO_AUTH]).await?;
+    let mut choice = [0u8; 2];
+    control.read_exact(&mut choice).await?;
+    if choice != [VERSION, NO_AUTH] {
+        return Err(invalid("does not accept unauthenticated clients"));
+    }
+
+    // The client address is left unspecified; the proxy learns it from
+    // the first datagram
+    let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+    push_addr(&mut request, SocketAddr::from(([0, 0, 0, 0], 0)));
+    control.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    control.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid("sent a malformed reply"));
+    }
+    if reply[1] != 0 {
+        return Err(invalid(&format!(
+            "refused UDP ASSOCIATE (reply code {})",
+            reply[1]
+        )));
+    }
+    let ip = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        _ => return Err(invalid("returned an unsupported relay address type")),
+    };
+    let port = control.read_u16().await?;
+    // An unspecified relay address means "the proxy's own address"
+    let relay = match ip.is_unspecified() {
+        true => SocketAddr::new(control.peer_addr()?.ip(), port),
+        false => SocketAddr::new(ip, port),
+    };
+    Ok((control, relay))
+}
+
+/// Appends ATYP, address and port as a SOCKS request encodes them.
+fn push_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
@@ -0,0 +1,530 @@
+use crate::error::SunriseError;
+use chrono::{DateTime, NaiveDate, Offset, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::Instrument;
+
+/// The top level of a response. Error responses carry a `status` such as
+/// `INVALID_REQUEST`, and may send an empty `results` or none at all.
+#[derive(Deserialize)]
+struct SunriseSunsetResponse {
+    #[serde(default)]
+    results: Option<serde_json::Value>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl SunriseSunsetResponse {
+    /// The `results` object, or [`SunriseError::NoResults`] with whatever the
+    /// API said went wrong when it's missing or not an object.
+    fn into_results(self, body: &str) -> Result<Results, SunriseError> {
+        match self.results {
+            Some(results @ serde_json::Value::Object(_)) => serde_json::from_value(results)
+                .map_err(|source| SunriseError::InvalidResponse {
+                    source,
+                    snippet: snippet(body),
+                }),
+            _ => {
+                let problem = [self.status, self.error, self.message]
+                    .into_iter()
+                    .flatten()
+                    .filter(|part| !part.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(": ");
+                Err(SunriseError::NoResults {
+                    problem: if problem.is_empty() {
+                        format!("no status given ({})", snippet(body))
+                    } else {
+                        problem
+                    },
+                })
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Results {
+    sunrise: String,
+    sunset: String,
+    solar_noon: String,
+    day_length: DayLength,
+    civil_twilight_begin: String,
+    // civil_twilight_end: String,
+    // nautical_twilight_begin: String,
+    // nautical_twilight_end: String,
+    // astronomical_twilight_begin: String,
+    // astronomical_twilight_end: String,
+}
+
+/// `day_length` is whole seconds with `formatted=0` and `HH:MM:SS` otherwise.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DayLength {
+    Seconds(i64),
+    Text(String),
+}
+
+impl DayLength {
+    fn to_duration(&self) -> Option<chrono::Duration> {
+        match self {
+            DayLength::Seconds(seconds) => Some(chrono::Duration::seconds(*seconds)),
+            DayLength::Text(text) => {
+                let mut parts = text.trim().split(':').map(|part| part.parse::<i64>().ok());
+                let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                else {
+                    return None;
+                };
+                Some(chrono::Duration::seconds(
+                    hours * 3600 + minutes * 60 + seconds,
+                ))
+            }
+        }
+    }
+}
+
+/// The solar events a day's schedule can be anchored to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SolarTimes {
+    pub sunrise: DateTime<Utc>,
+    pub sunset: DateTime<Utc>,
+    /// When the sun is 6° below the horizon before sunrise.
+    pub civil_twilight_begin: DateTime<Utc>,
+    /// When the sun is highest.
+    pub solar_noon: DateTime<Utc>,
+    /// Time from sunrise to sunset. Informational only.
+    pub day_length: chrono::Duration,
+}
+
+/// Fetches the sunrise for `date`, or for the API's notion of today (which
+/// follows UTC) when no date is given.
+pub async fn fetch_sunrise_time(
+    http: &reqwest::Client,
+    lat: f64,
+    lng: f64,
+    date: Option<NaiveDate>,
+    retries: u32,
+    tz: Option<Tz>,
+) -> Result<DateTime<Utc>, SunriseError> {
+    Ok(fetch_solar_times(http, lat, lng, date, retries, tz)
+        .await?
+        .sunrise)
+}
+
+/// Delay before the first retry; doubled for each one after it.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Fetches every solar event for `date` in a single API call, retrying up
+/// to `retries` times with exponential backoff when the failure is
+/// retryable (see [`SunriseError::is_retryable`]). With `tz`, the API is
+/// asked for localized times as a cross-check of our own conversion.
+pub async fn fetch_solar_times(
+    http: &reqwest::Client,
+    lat: f64,
+    lng: f64,
+    date: Option<NaiveDate>,
+    retries: u32,
+    tz: Option<Tz>,
+) -> Result<SolarTimes, SunriseError> {
+    let mut url = format!(
+        "https://api.sunrise-sunset.org/json?lat={}&lng={}&formatted=0",
+        lat, lng
+    );
+    if let Some(date) = date {
+        url.push_str(&format!("&date={}", date.format("%Y-%m-%d")));
+    }
+    if let Some(tz) = tz {
+        url.push_str(&format!("&tzid={}", tz.name()));
+    }
+
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let result = request_solar_times(http, &url, tz)
+            .instrument(tracing::info_span!("sunrise_fetch", attempt))
+            .await;
+        record_latency(started.elapsed());
+        match result {
+            Err(e) if attempt < retries && e.is_retryable() => {
+                let delay = RETRY_BASE_DELAY * 2u32.saturating_pow(attempt);
+                tracing::warn!(error = %e, ?delay, "retrying sunrise API request");
+                sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long the local sunrise service gets to answer.
+#[cfg(unix)]
+const UNIX_SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks the local service listening on the Unix socket at `path` for the
+/// solar times of `date`, with `SUNRISE_SOURCE=unix`. The request is one
+/// line of JSON, `{"lat":..,"lng":..,"date":"YYYY-MM-DD"}`; the service
+/// replies in the shape api.sunrise-sunset.org uses with `formatted=0` and
+/// closes the connection.
+#[cfg(unix)]
+pub async fn fetch_solar_times_unix(
+    path: &str,
+    lat: f64,
+    lng: f64,
+    date: NaiveDate,
+) -> Result<SolarTimes, SunriseError> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let exchange = async {
+        let mut stream = tokio::net::UnixStream::connect(path).await?;
+        let request = serde_json::json!({
+            "lat": lat,
+            "lng": lng,
+            "date": date.format("%Y-%m-%d").to_string(),
+        });
+        stream
+            .write_all(format!("{}\n", request).as_bytes())
+            .await?;
+        stream.shutdown().await?;
+        let mut body = String::new();
+        stream.read_to_string(&mut body).await?;
+        Ok::<_, std::io::Error>(body)
+    };
+    let body = tokio::time::timeout(UNIX_SOCKET_TIMEOUT, exchange)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    PARSER.parse(&body)
+}
+
+/// Sunrise API request durations kept for [`latency_summary`].
+const LATENCY_SAMPLES: usize = 256;
+
+/// The most recent request durations, oldest first, over the process's
+/// lifetime.
+static LATENCIES: Mutex<VecDeque<Duration>> = Mutex::new(VecDeque::new());
+
+fn record_latency(latency: Duration) {
+    let mut latencies = LATENCIES.lock().unwrap();
+    if latencies.len() == LATENCY_SAMPLES {
+        latencies.pop_front();
+    }
+    latencies.push_back(latency);
+}
+
+/// Percentiles of recent sunrise API request durations, failed attempts
+/// included.
+#[derive(Clone, Copy, Debug)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+/// Summarizes the last [`LATENCY_SAMPLES`] requests, or `None` before the
+/// first one.
+pub fn latency_summary() -> Option<LatencySummary> {
+    let mut latencies: Vec<Duration> = LATENCIES.lock().unwrap().iter().copied().collect();
+    latencies.sort();
+    let max = *latencies.last()?;
+    // Nearest-rank percentile
+    let percentile = |p: usize| latencies[(latencies.len() * p).div_ceil(100) - 1];
+    Some(LatencySummary {
+        samples: latencies.len(),
+        p50: percentile(50),
+        p95: percentile(95),
+        max,
+    })
+}
+
+async fn request_solar_times(
+    http: &reqwest::Client,
+    url: &str,
+    tz: Option<Tz>,
+) -> Result<SolarTimes, SunriseError> {
+    let response = http.get(url).send().await?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(SunriseError::Status {
+            status,
+            snippet: snippet(&body),
+        });
+    }
+    let times = PARSER.parse(&body)?;
+    if let Some(tz) = tz {
+        check_localized(&body, tz, &times);
+    }
+    Ok(times)
+}
+
+/// Warns when the UTC offset the API applied for `tzid` differs from the one
+/// chrono-tz gives for the same instant, which points at stale tz data on
+/// one side or the other.
+fn check_localized(body: &str, tz: Tz, times: &SolarTimes) {
+    let Ok(results) = serde_json::from_str::<SunriseSunsetResponse>(body)
+        .map_err(|_| ())
+        .and_then(|resp| resp.into_results(body).map_err(|_| ()))
+    else {
+        return;
+    };
+    let Ok(api_sunrise) = DateTime::parse_from_rfc3339(&results.sunrise) else {
+        return;
+    };
+    let ours = times.sunrise.with_timezone(&tz);
+    if api_sunrise.offset().fix() != ours.offset().fix() {
+        tracing::warn!(
+            api = %api_sunrise,
+            ours = %ours,
+            tz = tz.name(),
+            "sunrise API and local timezone conversion disagree"
+        );
+    }
+}
+
+/// Turns a provider's response body into [`SolarTimes`], so each API's JSON
+/// shape is handled apart from fetching.
+pub trait ResponseParser: Send + Sync {
+    fn parse(&self, body: &str) -> Result<SolarTimes, SunriseError>;
+}
+
+/// The parser for the provider requests are sent to.
+const PARSER: &dyn ResponseParser = &SunriseSunsetParser;
+
+/// Parses api.sunrise-sunset.org responses requested with `formatted=0`.
+pub struct SunriseSunsetParser;
+
+impl ResponseParser for SunriseSunsetParser {
+    fn parse(&self, body: &str) -> Result<SolarTimes, SunriseError> {
+        let resp: SunriseSunsetResponse =
+            serde_json::from_str(body).map_err(|source| SunriseError::InvalidResponse {
+                source,
+                snippet: snippet(body),
+            })?;
+        let results = resp.into_results(body)?;
+        let parse = |value: &str| {
+            value.parse::<DateTime<Utc>>().map_err(|source| {
+                // A mirror ignoring `formatted=0` sends times like "7:27:02 AM"
+                if is_formatted_time(value) {
+                    SunriseError::FormattedTimes {
+                        value: value.to_string(),
+                    }
+                } else {
+                    SunriseError::ChronoParseError {
+                        source,
+                        snippet: snippet(body),
+                    }
+                }
+            })
+        };
+        let sunrise = parse(&results.sunrise)?;
+        let sunset = parse(&results.sunset)?;
+        Ok(SolarTimes {
+            sunrise,
+            sunset,
+            civil_twilight_begin: parse(&results.civil_twilight_begin)?,
+            solar_noon: parse(&results.solar_noon)?,
+            day_length: results.day_length.to_duration().unwrap_or(sunset - sunrise),
+        })
+    }
+}
+
+/// Whether `value` looks like a `formatted=1` time such as `7:27:02 AM`,
+/// which carries no date or offset and can't be used.
+fn is_formatted_time(value: &str) -> bool {
+    let value = value.trim();
+    (value.ends_with(" AM") || value.ends_with(" PM"))
+        && value.contains(':')
+        && !value.contains('T')
+}
+
+/// Every solar event of a day from one API response, kept in memory so
+/// jobs anchored to different events share a single request per day, also
+/// when running without a database or its `daily_solar` table.
+#[derive(Debug, Default)]
+pub struct SolarCache {
+    days: Mutex<HashMap<NaiveDate, SolarTimes>>,
+}
+
+/// Days before the latest cached one that are kept.
+const SOLAR_CACHE_DAYS: i64 = 7;
+
+impl SolarCache {
+    pub fn get(&self, day: NaiveDate) -> Option<SolarTimes> {
+        self.days.lock().unwrap().get(&day).copied()
+    }
+
+    pub fn insert(&self, day: NaiveDate, times: SolarTimes) {
+        let mut days = self.days.lock().unwrap();
+        days.insert(day, times);
+        // A daemon adds a day at a time; drop the ones long past
+        if let Some(latest) = days.keys().max().copied() {
+            days.retain(|cached, _| (latest - *cached).num_days() <= SOLAR_CACHE_DAYS);
+        }
+    }
+}
+
+/// Stops calls to a failing API: after `threshold` consecutive failures the
+/// breaker opens for `cooldown`, after which a single probe is let through.
+/// A failed probe reopens it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// When the breaker will let the next call through, or `None` if calls
+    /// are allowed now.
+    pub fn open_until(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.open_until.filter(|until| *until > Instant::now())
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    /// Counts a failure; returns true when it opens the breaker.
+    pub fn record_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        if self.threshold == 0 || state.consecutive_failures < self.threshold {
+            return false;
+        }
+        state.open_until = Some(Instant::now() + self.cooldown);
+        true
+    }
+}
+
+/// Oldest recorded day the drift fallback will extrapolate from.
+const MAX_DRIFT_FALLBACK_DAYS: i64 = 3;
+
+/// Estimates `day`'s solar times from recent history (newest first) by
+/// carrying the latest record forward, adjusted by the day-over-day drift
+/// between the two latest records when both exist.
+pub fn estimate_from_history(
+    day: NaiveDate,
+    history: &[(NaiveDate, SolarTimes)],
+) -> Option<SolarTimes> {
+    let (latest_day, latest) = history.first()?;
+    let days_ahead = (day - *latest_day).num_days();
+    if !(1..=MAX_DRIFT_FALLBACK_DAYS).contains(&days_ahead) {
+        return None;
+    }
+
+    let drift = |pick: fn(&SolarTimes) -> DateTime<Utc>| match history.get(1) {
+        Some((previous_day, previous)) => {
+            let gap = (*latest_day - *previous_day).num_days();
+            let shift = pick(latest) - pick(previous) - chrono::Duration::days(gap);
+            shift / gap as i32
+        }
+        None => chrono::Duration::zero(),
+    };
+    let project = |pick: fn(&SolarTimes) -> DateTime<Utc>| {
+        pick(latest) + (chrono::Duration::days(1) + drift(pick)) * days_ahead as i32
+    };
+
+    let sunrise = project(|times| times.sunrise);
+    let sunset = project(|times| times.sunset);
+    Some(SolarTimes {
+        sunrise,
+        sunset,
+        civil_twilight_begin: project(|times| times.civil_twilight_begin),
+        solar_noon: project(|times| times.solar_noon),
+        day_length: sunset - sunrise,
+    })
+}
+
+/// Longest slice of a response body quoted in errors.
+const SNIPPET_LEN: usize = 200;
+
+/// The start of `body`, trimmed to `SNIPPET_LEN` characters, so errors show
+/// what the API actually returned (often an HTML error page).
+fn snippet(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `formatted=0` response, trimmed to the fields parsed.
+    const ISO_BODY: &str = r#"{"results":{"sunrise":"2026-10-15T05:27:02+00:00","sunset":"2026-10-15T16:12:40+00:00","solar_noon":"2026-10-15T10:49:51+00:00","day_length":38738,"civil_twilight_begin":"2026-10-15T04:58:31+00:00"},"status":"OK"}"#;
+
+    /// The same day as a provider ignoring `formatted=0` sends it.
+    const FORMATTED_BODY: &str = r#"{"results":{"sunrise":"5:27:02 AM","sunset":"4:12:40 PM","solar_noon":"10:49:51 AM","day_length":"10:45:38","civil_twilight_begin":"4:58:31 AM"},"status":"OK"}"#;
+
+    #[test]
+    fn parses_iso_8601_times() {
+        let times = SunriseSunsetParser.parse(ISO_BODY).unwrap();
+        assert_eq!(
+            times.sunrise,
+            "2026-10-15T05:27:02Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(times.day_length, chrono::Duration::seconds(38738));
+    }
+
+    #[test]
+    fn rejects_12_hour_times() {
+        match SunriseSunsetParser.parse(FORMATTED_BODY) {
+            Err(SunriseError::FormattedTimes { value }) => assert_eq!(value, "5:27:02 AM"),
+            other => panic!("expected FormattedTimes, got {:?}", other),
+        }
+    }
+
+    fn no_results_problem(body: &str) -> String {
+        let response: SunriseSunsetResponse = serde_json::from_str(body).unwrap();
+        match response.into_results(body) {
+            Err(SunriseError::NoResults { problem }) => problem,
+            Err(e) => panic!("expected NoResults, got {}", e),
+            Ok(_) => panic!("expected NoResults, got results"),
+        }
+    }
+
+    #[test]
+    fn reports_status_without_results() {
+        assert_eq!(
+            no_results_problem(r#"{"status":"INVALID_REQUEST"}"#),
+            "INVALID_REQUEST"
+        );
+        assert_eq!(
+            no_results_problem(r#"{"status":"INVALID_REQUEST","results":""}"#),
+            "INVALID_REQUEST"
+        );
+    }
+}
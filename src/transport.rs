@@ -0,0 +1,307 @@
+use crate::error::AppError;
+use crate::light::check_ack;
+use crate::socks::SocksUdp;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, enabled, warn, Level};
+
+/// Binds attempted before a socket error is returned.
+const BIND_ATTEMPTS: u32 = 5;
+
+/// Pause between bind attempts.
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Kernel buffer sizes for every UDP socket, from `UDP_SEND_BUF` and
+/// `UDP_RECV_BUF`. `None` keeps the OS default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketBuffers {
+    pub send: Option<usize>,
+    pub recv: Option<usize>,
+}
+
+/// The local address UDP sockets bind to and the buffer sizes they get;
+/// see [`crate::Config::udp_bind`].
+#[derive(Clone, Copy, Debug)]
+pub struct UdpBind {
+    pub addr: SocketAddr,
+    pub buffers: SocketBuffers,
+}
+
+/// Binds a UDP socket. Fails at once; [`wait_for_bind`] covers the startup
+/// race.
+pub async fn bind_udp(bind: UdpBind) -> Result<UdpSocket, AppError> {
+    Ok(bind_once(bind).await?)
+}
+
+/// Checks at startup that a UDP socket can be bound, retrying briefly: right
+/// after boot a container's network namespace may not be ready yet and the
+/// first bind can fail.
+pub async fn wait_for_bind(bind: UdpBind) -> Result<(), AppError> {
+    let mut attempt = 1;
+    loop {
+        match bind_once(bind).await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < BIND_ATTEMPTS => {
+                warn!(bind_addr = %bind.addr, attempt, error = %e, "UDP bind failed, retrying");
+                sleep(BIND_RETRY_DELAY).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Resolves a light's `host:port`, which may name a host rather than an IP
+/// address. Lookups are bounded by `wait` so a slow DNS server fails fast,
+/// apart from delivery errors.
+pub async fn resolve(addr: &str, wait: Duration) -> Result<SocketAddr, AppError> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+    let failed = |reason: String| AppError::Resolve {
+        host: addr.to_string(),
+        reason,
+    };
+    let mut addrs = timeout(wait, lookup_host(addr))
+        .await
+        .map_err(|_| failed(format!("timed out after {} ms", wait.as_millis())))?
+        .map_err(|e| failed(e.to_string()))?;
+    addrs
+        .next()
+        .ok_or_else(|| failed("no addresses found".to_string()))
+}
+
+async fn bind_once(bind: UdpBind) -> std::io::Result<UdpSocket> {
+    let UdpBind {
+        addr: bind_addr,
+        buffers,
+    } = bind;
+    if buffers.send.is_none() && buffers.recv.is_none() {
+        return UdpSocket::bind(bind_addr).await;
+    }
+
+    let socket = Socket::new(
+        Domain::for_address(bind_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if let Some(size) = buffers.send {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = buffers.recv {
+        socket.set_recv_buffer_size(size)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&bind_addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+pub async fn send_udp_packet(
+    bind: UdpBind,
+    addr: SocketAddr,
+    payload: &str,
+) -> Result<(), AppError> {
+    let socket = bind_udp(bind).await?;
+    trace_bytes("udp send", addr, payload.as_bytes());
+    let sent = socket.send_to(payload.as_bytes(), &addr).await?;
+    check_sent(addr, sent, payload.len())
+}
+
+pub async fn send_udp_broadcast(
+    bind: UdpBind,
+    addr: SocketAddr,
+    payload: &str,
+) -> Result<(), AppError> {
+    let socket = bind_udp(bind).await?;
+    socket.set_broadcast(true)?;
+    trace_bytes("udp broadcast", addr, payload.as_bytes());
+    let sent = socket.send_to(payload.as_bytes(), &addr).await?;
+    check_sent(addr, sent, payload.len())
+}
+
+/// Sends a request datagram to a bulb and returns its reply, waiting at most
+/// `wait`.
+pub async fn query_udp(
+    bind: UdpBind,
+    addr: SocketAddr,
+    payload: &str,
+    wait: Duration,
+) -> Result<Vec<u8>, AppError> {
+    let socket = bind_udp(bind).await?;
+    socket.connect(addr).await?;
+    trace_bytes("udp query", addr, payload.as_bytes());
+    let sent = socket.send(payload.as_bytes()).await?;
+    check_sent(addr, sent, payload.len())?;
+
+    let mut reply = [0u8; 2048];
+    let read = timeout(wait, socket.recv(&mut reply))
+        .await
+        .map_err(|_| AppError::NoReply(addr.to_string(), wait))??;
+    trace_bytes("udp reply", addr, &reply[..read]);
+    Ok(reply[..read].to_vec())
+}
+
+/// [`send_udp_packet`] tunnelled through the SOCKS5 proxy at `proxy`.
+pub async fn send_udp_socks(
+    proxy: &str,
+    bind: UdpBind,
+    addr: SocketAddr,
+    payload: &str,
+) -> Result<(), AppError> {
+    let relay = SocksUdp::associate(proxy, bind).await?;
+    trace_bytes("udp send via socks", addr, payload.as_bytes());
+    let sent = relay.send_to(payload.as_bytes(), addr).await?;
+    check_sent(addr, sent, payload.len())
+}
+
+/// [`query_udp`] tunnelled through the SOCKS5 proxy at `proxy`.
+pub async fn query_udp_socks(
+    proxy: &str,
+    bind: UdpBind,
+    addr: SocketAddr,
+    payload: &str,
+    wait: Duration,
+) -> Result<Vec<u8>, AppError> {
+    let relay = SocksUdp::associate(proxy, bind).await?;
+    trace_bytes("udp query via socks", addr, payload.as_bytes());
+    let sent = relay.send_to(payload.as_bytes(), addr).await?;
+    check_sent(addr, sent, payload.len())?;
+
+    let mut reply = [0u8; 2048];
+    let read = timeout(wait, relay.recv(&mut reply))
+        .await
+        .map_err(|_| AppError::NoReply(addr.to_string(), wait))??;
+    trace_bytes("udp reply via socks", addr, &reply[..read]);
+    Ok(reply[..read].to_vec())
+}
+
+/// How long the self-test waits for its own datagram.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sends a datagram through [`send_udp_packet`] to a responder bound on
+/// loopback and checks it arrives intact, which rules out a broken local
+/// UDP stack or a container policy blocking sends.
+pub async fn udp_self_test(bind: UdpBind) -> Result<(), AppError> {
+    let responder = UdpSocket::bind("127.0.0.1:0").await?;
+    let responder_addr = responder.local_addr()?;
+    let payload = r#"{"method":"selfTest","params":{}}"#;
+    send_udp_packet(bind, responder_addr, payload).await?;
+
+    let mut buf = [0u8; 256];
+    let (len, _) = timeout(SELF_TEST_TIMEOUT, responder.recv_from(&mut buf))
+        .await
+        .map_err(|_| AppError::NoReply(responder_addr.to_string(), SELF_TEST_TIMEOUT))??;
+    if &buf[..len] != payload.as_bytes() {
+        return Err(AppError::InvalidReply(format!(
+            "self-test datagram arrived altered ({} of {} bytes)",
+            len,
+            payload.len()
+        )));
+    }
+    Ok(())
+}
+
+/// A datagram sent short is truncated JSON, which bulbs silently ignore.
+fn check_sent(addr: SocketAddr, sent: usize, expected: usize) -> Result<(), AppError> {
+    if sent == expected {
+        return Ok(());
+    }
+    warn!(%addr, sent, expected, "partial UDP send");
+    Err(AppError::PartialSend {
+        addr: addr.to_string(),
+        sent,
+        expected,
+    })
+}
+
+/// How long to wait for a bulb to accept a TCP connection, and then for its
+/// whole reply.
+const TCP_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest TCP reply read before giving up on it being complete.
+const MAX_TCP_REPLY: usize = 8 * 1024;
+
+/// Sends the payload over a TCP connection. With `require_reply`, waits for
+/// the bulb's reply and checks that it acknowledges the command.
+pub async fn send_tcp_packet(
+    addr: SocketAddr,
+    payload: &str,
+    require_reply: bool,
+) -> Result<(), AppError> {
+    let mut stream = timeout(TCP_REPLY_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| AppError::NoReply(addr.to_string(), TCP_REPLY_TIMEOUT))??;
+    trace_bytes("tcp send", addr, payload.as_bytes());
+    stream.write_all(payload.as_bytes()).await?;
+    if !require_reply {
+        return Ok(());
+    }
+
+    let reply = timeout(TCP_REPLY_TIMEOUT, read_json_reply(&mut stream))
+        .await
+        .map_err(|_| AppError::NoReply(addr.to_string(), TCP_REPLY_TIMEOUT))??;
+    if reply.is_empty() {
+        return Err(AppError::NoReply(addr.to_string(), TCP_REPLY_TIMEOUT));
+    }
+    trace_bytes("tcp reply", addr, &reply);
+    check_ack(&reply)
+}
+
+/// Reads until the bytes so far hold a complete JSON value, the bulb closes
+/// the connection or [`MAX_TCP_REPLY`] is reached, since a reply may arrive
+/// over several reads.
+async fn read_json_reply(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !is_complete_json(&reply) && reply.len() < MAX_TCP_REPLY {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..read]);
+    }
+    Ok(reply)
+}
+
+fn is_complete_json(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && serde_json::from_slice::<serde::de::IgnoredAny>(bytes).is_ok()
+}
+
+/// Logs a datagram's peer and contents as hex and ASCII at DEBUG level.
+fn trace_bytes(direction: &str, peer: SocketAddr, bytes: &[u8]) {
+    if !enabled!(Level::DEBUG) {
+        return;
+    }
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let ascii: String = bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    debug!(%peer, len = bytes.len(), %hex, %ascii, "{}", direction);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_json_is_incomplete() {
+        let reply = br#"{"method":"setPilot","result":{"success":true}}"#;
+        assert!(is_complete_json(reply));
+        assert!(!is_complete_json(&reply[..20]));
+        assert!(!is_complete_json(b""));
+    }
+}
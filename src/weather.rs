@@ -0,0 +1,98 @@
+use crate::error::AppError;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use std::sync::Mutex;
+
+const FORECAST_URL: &str = "https://api.openweathermap.org/data/2.5/forecast";
+
+#[derive(Deserialize)]
+struct ForecastResponse {
+    list: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    dt: i64,
+    clouds: Clouds,
+}
+
+#[derive(Deserialize)]
+struct Clouds {
+    all: u8,
+}
+
+/// Cloud cover forecast for one 3-hour slot.
+#[derive(Clone, Copy, Debug)]
+struct CloudCover {
+    at: DateTime<Utc>,
+    percent: u8,
+}
+
+/// The forecast fetched for a day, so another job that morning doesn't call
+/// the API again.
+#[derive(Debug, Default)]
+pub struct WeatherCache(Mutex<Option<(NaiveDate, Vec<CloudCover>)>>);
+
+/// Returns the forecast cloud cover, in percent, for the slot nearest `at`,
+/// from the OpenWeatherMap 5-day forecast. `day` keys the cache.
+pub async fn cloud_cover(
+    http: &reqwest::Client,
+    api_key: &str,
+    lat: f64,
+    lng: f64,
+    day: NaiveDate,
+    at: DateTime<Utc>,
+    cache: &WeatherCache,
+) -> Result<u8, AppError> {
+    let cached = match &*cache.0.lock().unwrap() {
+        Some((cached_day, slots)) if *cached_day == day => Some(slots.clone()),
+        _ => None,
+    };
+    let slots = match cached {
+        Some(slots) => slots,
+        None => {
+            let slots = fetch_forecast(http, api_key, lat, lng).await?;
+            *cache.0.lock().unwrap() = Some((day, slots.clone()));
+            slots
+        }
+    };
+
+    slots
+        .iter()
+        .min_by_key(|slot| (slot.at - at).num_seconds().abs())
+        .map(|slot| slot.percent)
+        .ok_or_else(|| AppError::Weather("forecast has no entries".to_string()))
+}
+
+async fn fetch_forecast(
+    http: &reqwest::Client,
+    api_key: &str,
+    lat: f64,
+    lng: f64,
+) -> Result<Vec<CloudCover>, AppError> {
+    let response: ForecastResponse = http
+        .get(FORECAST_URL)
+        .query(&[
+            ("lat", lat.to_string()),
+            ("lon", lng.to_string()),
+            ("appid", api_key.to_string()),
+        ])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| AppError::Weather(e.without_url().to_string()))?
+        .json()
+        .await
+        .map_err(|e| AppError::Weather(e.to_string()))?;
+
+    Ok(response
+        .list
+        .into_iter()
+        .filter_map(|entry| {
+            Some(CloudCover {
+                at: DateTime::from_timestamp(entry.dt, 0)?,
+                percent: entry.clouds.all,
+            })
+        })
+        .collect())
+}